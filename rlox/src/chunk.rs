@@ -1,11 +1,13 @@
-use crate::prelude::Value;
+use crate::{hashtable::HashTable, prelude::Value};
 
 #[derive(Clone, Copy, Debug)]
 pub enum OpCode {
     Return,
     // TODO(memory): This makes every opcode consume 2 bytes.
     // Maybe store [u8] as chunk instead of [OpCode] like book does?
-    Constant { location: u8 },
+    Constant {
+        location: u8,
+    },
     Negate,
 
     Nil,
@@ -18,20 +20,196 @@ pub enum OpCode {
     Less,
 
     Add,
+    /// Convert top of stack to its printed string form
+    Stringify,
     Subtract,
     Multiply,
     Divide,
+    /// Remainder with the sign of the dividend
+    Modulo,
+    /// Right associative `a ** b`
+    Power,
+    /// `a ~/ b`, division rounded towards negative infinity
+    IntegerDivide,
+    // Bitwise ops work on operands converted to i64, the operands must be integral
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
 
     Print,
     Pop,
-    DefineGlobal { location: u8 },
-    GetGlobal { location: u8 },
-    SetGlobal { location: u8 },
-    GetLocal { stack_idx: u8 },
-    SetLocal { stack_idx: u8 },
-    JumpIfFalse { offset: u16 },
-    Jump { offset: u16 },
-    Loop { offset: u16 },
+    /// Push a copy of the value `distance` slots below top of stack
+    Dup {
+        distance: u8,
+    },
+    /// Move top of stack below the `depth` values under it
+    Bury {
+        depth: u8,
+    },
+    DefineGlobal {
+        location: u8,
+    },
+    /// Like `DefineGlobal`, but the global can't be assigned or redefined afterwards
+    DefineConstGlobal {
+        location: u8,
+    },
+    GetGlobal {
+        location: u8,
+    },
+    SetGlobal {
+        location: u8,
+    },
+    GetLocal {
+        stack_idx: u8,
+    },
+    SetLocal {
+        stack_idx: u8,
+    },
+    JumpIfFalse {
+        offset: u16,
+    },
+    /// Jump without popping unless top of stack is nil
+    JumpIfNotNil {
+        offset: u16,
+    },
+    Jump {
+        offset: u16,
+    },
+    /// Skip a parameter's default when the call passed more than `index` arguments
+    JumpIfArgPassed {
+        index: u8,
+        offset: u16,
+    },
+    Loop {
+        offset: u16,
+    },
+    /// Jump to the `match` case of the value on top of stack, see `Chunk::jump_tables`
+    MatchJump {
+        table: u8,
+    },
+    Call {
+        arg_count: u8,
+    },
+    /// Wraps the function constant at `location` in a closure,
+    /// captures are described by the function itself
+    Closure {
+        location: u8,
+    },
+    GetUpvalue {
+        index: u8,
+    },
+    SetUpvalue {
+        index: u8,
+    },
+    CloseUpvalue,
+    Class {
+        location: u8,
+    },
+    GetProperty {
+        location: u8,
+    },
+    SetProperty {
+        location: u8,
+    },
+    /// Attach the closure on top of stack as a method to the class below it
+    Method {
+        location: u8,
+    },
+    /// Like `Method` for `get name() {...}` and `set name(value) {...}`
+    Getter {
+        location: u8,
+    },
+    Setter {
+        location: u8,
+    },
+    /// Store the value on top of stack as a `static` member of the class below it
+    StaticMember {
+        location: u8,
+    },
+    /// `receiver.name(args)` in a single step, `location` refers to the name
+    Invoke {
+        location: u8,
+        arg_count: u8,
+    },
+    /// `Call`, `Invoke` and `SuperInvoke` with a spread argument.
+    /// Arguments are in `segments` lists that get flattened first.
+    CallSpread {
+        segments: u8,
+    },
+    InvokeSpread {
+        location: u8,
+        segments: u8,
+    },
+    SuperInvokeSpread {
+        location: u8,
+        segments: u8,
+    },
+    /// Copy methods of the superclass below the top of stack into the class on top
+    Inherit,
+    GetSuper {
+        location: u8,
+    },
+    /// `super.name(args)` without creating a bound method
+    SuperInvoke {
+        location: u8,
+        arg_count: u8,
+    },
+    /// Throw the value on top of stack
+    Throw,
+    /// Suspend the running generator, handing the value on top of stack to whoever resumed it
+    Yield,
+    /// Pops the flag and value a `finally` block keeps below its locals,
+    /// rethrows the value if the flag is set
+    EndFinally,
+    /// Push the namespace of the module whose path is at `location`
+    Import {
+        location: u8,
+    },
+    /// Make the global named at `location` visible to importers
+    Export {
+        location: u8,
+    },
+    /// Collect `count` values from top of stack into a new list
+    BuildList {
+        count: u8,
+    },
+    /// Collect `count` key/value pairs from top of stack into a new map
+    BuildMap {
+        count: u8,
+    },
+    /// Range from the two numbers on top of stack
+    BuildRange {
+        inclusive: bool,
+    },
+    /// `value in container`
+    Contains,
+    /// `value is Enum.Variant`
+    IsVariant,
+    /// Replace the list on top of stack with its `count` items
+    UnpackList {
+        count: u8,
+    },
+    /// Replace the instance or map on top of stack with its field or key named at `location`
+    UnpackField {
+        location: u8,
+    },
+    /// `target[index]`, a range index slices a list
+    IndexGet,
+    /// `target[index] = value`
+    IndexSet,
+    /// Replace the iterable on top of stack with an iterator over it
+    IterInit,
+    /// Push whether the iterator in local `stack_idx` has more values
+    IterHasNext {
+        stack_idx: u8,
+    },
+    /// Push the next value of the iterator in local `stack_idx`
+    IterNext {
+        stack_idx: u8,
+    },
 }
 
 type LineNo = usize;
@@ -40,6 +218,70 @@ type LineNo = usize;
 pub struct Chunk {
     pub code: Vec<(OpCode, LineNo)>,
     pub constants: Vec<Value>,
+    /// Innermost handlers come first
+    pub handlers: Vec<Handler>,
+    pub jump_tables: Vec<JumpTable>,
+}
+
+/// Where to continue when an exception is thrown by code in `start..end`
+#[derive(Debug, Clone, Copy)]
+pub struct Handler {
+    pub start: usize,
+    pub end: usize,
+    pub target: usize,
+    /// Stack slots, relative to the frame, that stay alive. The exception is pushed above them.
+    pub stack_depth: usize,
+}
+
+/// Case targets of a `match` for integer and string literals
+#[derive(Debug, Clone)]
+pub struct JumpTable {
+    /// Target of integer case `min + i` is `dense[i]`
+    pub min: f64,
+    pub dense: Vec<Option<usize>>,
+    /// Strings and integers too spread out for `dense`, targets are stored as numbers
+    pub sparse: HashTable,
+    /// Where to continue when no case in the table matches
+    pub otherwise: usize,
+}
+
+impl JumpTable {
+    /// Integers are kept `dense` if that leaves at most half of its slots empty.
+    /// When a value appears in several cases the first one wins.
+    pub fn new(cases: Vec<(Value, usize)>, otherwise: usize) -> Self {
+        let ints: Vec<f64> = cases
+            .iter()
+            .filter_map(|(value, _)| value.as_number().copied())
+            .collect();
+        let min = ints.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = ints.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let is_dense = !ints.is_empty() && max - min < 2.0 * ints.len() as f64;
+
+        let mut table = Self {
+            min: if is_dense { min } else { 0.0 },
+            dense: if is_dense {
+                vec![None; (max - min) as usize + 1]
+            } else {
+                vec![]
+            },
+            sparse: HashTable::new(),
+            otherwise,
+        };
+        for (value, target) in cases {
+            match value {
+                Value::Number(x) if is_dense => {
+                    let slot = &mut table.dense[(x - min) as usize];
+                    slot.get_or_insert(target);
+                }
+                value => {
+                    if table.sparse.get(&value).is_none() {
+                        table.sparse.set(value, Value::Number(target as f64));
+                    }
+                }
+            }
+        }
+        table
+    }
 }
 
 impl Chunk {
@@ -47,6 +289,8 @@ impl Chunk {
         return Self {
             code: vec![],
             constants: vec![],
+            handlers: vec![],
+            jump_tables: vec![],
         };
     }
 
@@ -2,7 +2,7 @@ use std::io::Write;
 
 use crate::hashtable::HashTable;
 use crate::prelude::*;
-use crate::value::LoxObject;
+use crate::value::{Capture, LoxObject};
 use crate::{
     prelude::Chunk,
     scanner::{Scanner, Token, TokenType, TokenType::*},
@@ -13,6 +13,178 @@ const U8_COUNT: usize = u8::MAX as usize + 1;
 pub struct Local<'a> {
     name: Token<'a>,
     depth: Option<usize>, // None if unitialized
+    /// A closure refers to this local, so it has to be moved to heap
+    /// instead of being popped when its scope ends
+    is_captured: bool,
+    /// Declared with `const`, assignments to it are rejected
+    is_const: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Getter,
+    Setter,
+    Script,
+}
+
+/// Innermost enclosing loop, `break` and `continue` jump relative to it
+pub struct LoopState {
+    /// Where `continue` jumps back to
+    continue_target: usize,
+    /// Locals deeper than this belong to the loop body
+    scope_depth: usize,
+    /// `break` jumps to be patched once the loop ends
+    break_jumps: Vec<usize>,
+}
+
+/// Way out of a `try` that has to pass through its `finally` block first
+#[derive(Clone, Copy, PartialEq)]
+enum TryExit {
+    /// Return the value stored in the `try`'s hidden value slot
+    Return,
+    /// `break` out of the loop enclosing the `try`
+    Break,
+    /// `continue` the loop enclosing the `try`
+    Continue,
+}
+
+/// Innermost enclosing `try`, while its block or `catch` clause is compiled
+pub struct TryState {
+    /// Locals at the start of the `try` block, its hidden value and flag are the last two
+    stack_depth: usize,
+    /// Loops enclosing the `try`, `break` and `continue` of deeper loops don't leave it
+    loop_count: usize,
+    /// Exits taken so far, the flag holds the index of the one being taken
+    exits: Vec<TryExit>,
+    /// Jumps of those exits to be patched to the start of `finally`
+    exit_jumps: Vec<usize>,
+}
+
+/// Everything that is specific to the function currently being compiled.
+/// Nested function declarations push a new state and pop it once their body ends.
+pub struct FunctionState<'a> {
+    name: Option<std::string::String>,
+    arity: usize,
+    optional: usize,
+    variadic: bool,
+    /// Declared with `fun*`, its body may `yield`
+    generator: bool,
+    chunk: Chunk,
+    ty: FunctionType,
+
+    locals: smallvec::SmallVec<[Local<'a>; U8_COUNT]>,
+    upvalues: Vec<Capture>,
+    scope_depth: usize,
+    loops: Vec<LoopState>,
+    tries: Vec<TryState>,
+}
+
+impl<'a> FunctionState<'a> {
+    fn new(ty: FunctionType, name: Option<std::string::String>) -> Self {
+        let mut locals = smallvec::SmallVec::new();
+
+        // Slot zero holds the function being called, it can never be referenced by name.
+        // Methods keep their receiver there instead, which is reachable as `this`.
+        let slot_zero = match ty {
+            FunctionType::Method
+            | FunctionType::Initializer
+            | FunctionType::Getter
+            | FunctionType::Setter => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        locals.push(Local {
+            name: Token {
+                ty: Identifier,
+                string: slot_zero,
+                line: 0,
+            },
+            depth: Some(0),
+            is_captured: false,
+            is_const: false,
+        });
+
+        Self {
+            name,
+            arity: 0,
+            optional: 0,
+            variadic: false,
+            generator: false,
+            chunk: Chunk::new(),
+            ty,
+            locals,
+            upvalues: vec![],
+            scope_depth: 0,
+            loops: vec![],
+            tries: vec![],
+        }
+    }
+}
+
+/// Something `=`, `+=` or `++` can write to.
+/// Operands of the target (receiver, index) are already on the stack.
+#[derive(Clone, Copy)]
+enum AssignTarget<'a> {
+    /// Local, upvalue or global
+    Named {
+        get: OpCode,
+        set: OpCode,
+        is_const: bool,
+    },
+    /// Global `const` with a literal initializer, read straight from the constant at `value`.
+    /// Its name only takes a constant slot if something tries to assign it.
+    ConstGlobal { value: u8, name: Token<'a> },
+    /// `receiver.name`
+    Property { location: u8 },
+    /// `target[index]`
+    Index,
+}
+
+impl AssignTarget<'_> {
+    /// Number of values the target keeps on the stack
+    fn operands(&self) -> u8 {
+        match self {
+            AssignTarget::Named { .. } | AssignTarget::ConstGlobal { .. } => 0,
+            AssignTarget::Property { .. } => 1,
+            AssignTarget::Index => 2,
+        }
+    }
+
+    fn get_op(&self) -> OpCode {
+        match *self {
+            AssignTarget::Named { get, .. } => get,
+            AssignTarget::ConstGlobal { value, .. } => OpCode::Constant { location: value },
+            AssignTarget::Property { location } => OpCode::GetProperty { location },
+            AssignTarget::Index => OpCode::IndexGet,
+        }
+    }
+}
+
+/// Left-hand side of a destructuring declaration or assignment
+enum Pattern<'a> {
+    Name(Token<'a>),
+    /// `[a, b]`
+    List(Vec<Pattern<'a>>),
+    /// `{x, y: pattern}`, each field name along with where its value goes
+    Object(Vec<(Token<'a>, Pattern<'a>)>),
+}
+
+impl<'a> Pattern<'a> {
+    /// Every name the pattern binds, in source order
+    fn names(&self, names: &mut Vec<Token<'a>>) {
+        match self {
+            Pattern::Name(name) => names.push(*name),
+            Pattern::List(items) => items.iter().for_each(|item| item.names(names)),
+            Pattern::Object(fields) => fields.iter().for_each(|(_, target)| target.names(names)),
+        }
+    }
+}
+
+/// Tracks the class whose body is being compiled
+pub struct ClassState {
+    has_superclass: bool,
 }
 
 pub struct Compiler<'a, 'b, WE: Write> {
@@ -21,11 +193,26 @@ pub struct Compiler<'a, 'b, WE: Write> {
     previous: Option<Token<'a>>,
     had_error: bool,
     panic_mode: bool,
-    chunk: Chunk,
     strings: HashTable,
 
-    locals: smallvec::SmallVec<[Local<'a>; U8_COUNT]>,
-    scope_depth: usize,
+    /// Innermost function is at the end
+    states: Vec<FunctionState<'a>>,
+
+    /// Innermost class is at the end
+    classes: Vec<ClassState>,
+
+    /// Values of `const` globals with a literal initializer, keyed by name.
+    /// Reads of these are compiled to the value itself.
+    const_globals: HashTable,
+
+    /// Nesting level of `parse_precedence` calls
+    expression_depth: usize,
+    /// Operator of the innermost prefix `++`/`--` waiting for its target,
+    /// along with the expression depth the target has to be parsed at
+    prefix_increment: Option<(OpCode, usize)>,
+    /// Set while a match case value is parsed, the `=>` after `(a)` there ends the case
+    /// instead of starting an arrow function
+    case_value: bool,
 
     stderr: &'b mut WE,
 }
@@ -33,7 +220,7 @@ macro_rules! emit_jump {
     ($compiler:ident, $enum_variant:ident) => {{
         let a = OpCode::$enum_variant { offset: 0 };
         $compiler.emit_op(a);
-        $compiler.chunk.code.len()
+        $compiler.current_chunk().code.len()
     }};
 }
 
@@ -41,13 +228,24 @@ macro_rules! emit_jump {
 pub enum Precedence {
     None,
     Assignment,
+    /// cond ? a : b
+    Conditional,
+    /// a ?? b
+    Coalesce,
     Or,
     And,
     Equality,
     Comparison,
+    /// a..b
+    Range,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
     Term,
     Factor,
     Unary,
+    Exponent,
     Call,
     Primary,
     PlaceHolderHighest,
@@ -58,14 +256,22 @@ impl Precedence {
         use Precedence::*;
         match self {
             None => Assignment,
-            Assignment => Or,
+            Assignment => Conditional,
+            Conditional => Coalesce,
+            Coalesce => Or,
             Or => And,
             And => Equality,
             Equality => Comparison,
-            Comparison => Term,
+            Comparison => Range,
+            Range => BitOr,
+            BitOr => BitXor,
+            BitXor => BitAnd,
+            BitAnd => Shift,
+            Shift => Term,
             Term => Factor,
             Factor => Unary,
-            Unary => Call,
+            Unary => Exponent,
+            Exponent => Call,
             Call => Primary,
             Primary => PlaceHolderHighest,
             _ => unreachable!(),
@@ -92,11 +298,16 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
             previous: None,
             had_error: false,
             panic_mode: false,
-            chunk: Chunk::new(),
             strings,
 
-            locals: smallvec::SmallVec::new(),
-            scope_depth: 0,
+            states: vec![FunctionState::new(FunctionType::Script, None)],
+            classes: vec![],
+
+            const_globals: HashTable::new(),
+
+            expression_depth: 0,
+            prefix_increment: None,
+            case_value: false,
 
             stderr,
         }
@@ -106,21 +317,34 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
         source: &'a str,
         strings: HashTable,
         stderr: &'b mut WE,
-    ) -> Option<(Chunk, HashTable)> {
+    ) -> (Option<LoxObject>, HashTable) {
         let mut compiler = Self::new(source, strings, stderr);
         compiler.advance();
         while !compiler.match_(Eof) {
             compiler.declaration();
         }
         compiler.consume(TokenType::Eof, "Expect End of expression.");
-        compiler.end_compiler();
+        let function = compiler.end_compiler();
+        // The interned strings are handed back even on error, the VM keeps using them
         if compiler.had_error {
-            return None;
+            return (None, compiler.strings);
         } else {
-            return Some((compiler.chunk, compiler.strings));
+            return (Some(function), compiler.strings);
         }
     }
 
+    fn state(&self) -> &FunctionState<'a> {
+        self.states.last().unwrap()
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState<'a> {
+        self.states.last_mut().unwrap()
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().chunk
+    }
+
     fn expression(&mut self) {
         self.parse_precedence(Precedence::Assignment);
     }
@@ -153,11 +377,13 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
         self.consume(RightParen, "Expect ')' after condition.");
 
         let then_jump = emit_jump!(self, JumpIfFalse);
+        self.emit_op(OpCode::Pop);
 
         self.statement();
 
         let else_jump = emit_jump!(self, Jump);
         self.patch_jump(then_jump);
+        self.emit_op(OpCode::Pop);
 
         if self.match_(Else) {
             self.statement();
@@ -165,9 +391,244 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
         self.patch_jump(else_jump);
     }
 
+    fn return_statement(&mut self) {
+        if self.state().ty == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_(Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state().ty == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+            if self.state().ty == FunctionType::Setter {
+                self.error("Can't return a value from a setter.");
+            }
+            if self.state().generator {
+                self.error("Can't return a value from a generator.");
+            }
+            self.expression();
+            self.consume(Semicolon, "Expect ';' after return value.");
+            self.emit_return_value();
+        }
+    }
+
+    fn yield_statement(&mut self) {
+        if !self.state().generator {
+            self.error("Can't use 'yield' outside of a generator.");
+        }
+
+        if self.match_(Semicolon) {
+            self.emit_op(OpCode::Nil);
+        } else {
+            self.expression();
+            self.consume(Semicolon, "Expect ';' after yielded value.");
+        }
+        self.emit_op(OpCode::Yield);
+    }
+
+    fn throw_statement(&mut self) {
+        self.expression();
+        self.consume(Semicolon, "Expect ';' after thrown value.");
+        self.emit_op(OpCode::Throw);
+    }
+
+    fn try_statement(&mut self) {
+        // try {...} catch (e) {...} finally {...}
+        //
+        // Two hidden locals live for the whole statement: the value being thrown or returned
+        // and a flag, nil on the normal way out, true when throwing, and the index of the
+        // exit when a `return`, `break` or `continue` leaves the `try`.
+        self.begin_scope();
+        self.emit_ops(OpCode::Nil, OpCode::Nil);
+        self.add_local(Self::synthetic_token(""));
+        self.mark_initialized();
+        self.add_local(Self::synthetic_token(""));
+        self.mark_initialized();
+
+        let stack_depth = self.state().locals.len();
+        let loop_count = self.state().loops.len();
+        self.state_mut().tries.push(TryState {
+            stack_depth,
+            loop_count,
+            exits: vec![],
+            exit_jumps: vec![],
+        });
+
+        let try_start = self.current_chunk().code.len();
+        self.consume(LeftBrace, "Expect '{' after 'try'.");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        let try_end = self.current_chunk().code.len();
+        let success_jump = emit_jump!(self, Jump);
+
+        let has_catch = self.match_(Catch);
+        let mut catch_range = None;
+        if has_catch {
+            let catch_start = self.current_chunk().code.len();
+            self.add_handler(try_start, try_end, catch_start, stack_depth);
+
+            // The unwinder pushed the exception, it becomes the catch variable
+            self.begin_scope();
+            if self.match_(LeftParen) {
+                self.consume(Identifier, "Expect exception variable name.");
+                self.declare_variable();
+                self.consume(RightParen, "Expect ')' after exception variable.");
+            } else {
+                self.add_local(Self::synthetic_token(""));
+            }
+            self.mark_initialized();
+            self.consume(LeftBrace, "Expect '{' after catch clause.");
+            self.block();
+            self.end_scope();
+            catch_range = Some((catch_start, self.current_chunk().code.len()));
+        }
+        self.patch_jump(success_jump);
+
+        // Exits from the `finally` block itself don't come back to it
+        let try_state = self.state_mut().tries.pop().unwrap();
+        let (value_slot, flag_slot) = (stack_depth as u8 - 2, stack_depth as u8 - 1);
+
+        if self.match_(Finally) {
+            let finally_jump = emit_jump!(self, Jump);
+
+            let throw_target = self.current_chunk().code.len();
+            match catch_range {
+                Some((start, end)) => self.add_handler(start, end, throw_target, stack_depth),
+                None => self.add_handler(try_start, try_end, throw_target, stack_depth),
+            }
+            self.emit_ops(
+                OpCode::SetLocal {
+                    stack_idx: value_slot,
+                },
+                OpCode::Pop,
+            );
+            self.emit_op(OpCode::True);
+            self.emit_ops(
+                OpCode::SetLocal {
+                    stack_idx: flag_slot,
+                },
+                OpCode::Pop,
+            );
+            self.patch_jump(finally_jump);
+            for jump in try_state.exit_jumps {
+                self.patch_jump(jump);
+            }
+
+            self.consume(LeftBrace, "Expect '{' after 'finally'.");
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+            self.resume_try_exits(&try_state.exits, value_slot, flag_slot);
+
+            // EndFinally pops the hidden locals itself
+            self.state_mut().scope_depth -= 1;
+            self.state_mut().locals.pop();
+            self.state_mut().locals.pop();
+            self.emit_op(OpCode::EndFinally);
+        } else {
+            if !has_catch {
+                self.error_at_current("Expect 'catch' or 'finally' after try block.");
+            }
+            for jump in try_state.exit_jumps {
+                self.patch_jump(jump);
+            }
+            self.resume_try_exits(&try_state.exits, value_slot, flag_slot);
+            self.end_scope();
+        }
+    }
+
+    /// Leave the innermost `try` through `exit`, by way of its `finally` block.
+    /// A returned value is expected on top of stack.
+    fn exit_try(&mut self, exit: TryExit) {
+        let state = self.state().tries.last().unwrap();
+        let stack_depth = state.stack_depth;
+        let index = match state.exits.iter().position(|x| *x == exit) {
+            Some(index) => index,
+            None => {
+                let state = self.state_mut().tries.last_mut().unwrap();
+                state.exits.push(exit);
+                state.exits.len() - 1
+            }
+        };
+
+        if exit == TryExit::Return {
+            self.emit_ops(
+                OpCode::SetLocal {
+                    stack_idx: stack_depth as u8 - 2,
+                },
+                OpCode::Pop,
+            );
+        }
+        let ops: Vec<_> = self.state().locals[stack_depth..]
+            .iter()
+            .rev()
+            .map(|local| {
+                if local.is_captured {
+                    OpCode::CloseUpvalue
+                } else {
+                    OpCode::Pop
+                }
+            })
+            .collect();
+        for op in ops {
+            self.emit_op(op);
+        }
+        self.emit_constant(Value::Number(index as f64));
+        self.emit_ops(
+            OpCode::SetLocal {
+                stack_idx: stack_depth as u8 - 1,
+            },
+            OpCode::Pop,
+        );
+        let jump = emit_jump!(self, Jump);
+        self.state_mut()
+            .tries
+            .last_mut()
+            .unwrap()
+            .exit_jumps
+            .push(jump);
+    }
+
+    /// After a `finally` block, carry on with the exit that led into it, if any
+    fn resume_try_exits(&mut self, exits: &[TryExit], value_slot: u8, flag_slot: u8) {
+        for (index, exit) in exits.iter().enumerate() {
+            self.emit_op(OpCode::GetLocal {
+                stack_idx: flag_slot,
+            });
+            self.emit_constant(Value::Number(index as f64));
+            self.emit_op(OpCode::Equal);
+            let skip_jump = emit_jump!(self, JumpIfFalse);
+            self.emit_op(OpCode::Pop);
+            match exit {
+                TryExit::Return => {
+                    self.emit_op(OpCode::GetLocal {
+                        stack_idx: value_slot,
+                    });
+                    self.emit_return_value();
+                }
+                TryExit::Break => self.emit_break(),
+                TryExit::Continue => self.emit_continue(),
+            }
+            self.patch_jump(skip_jump);
+            self.emit_op(OpCode::Pop);
+        }
+    }
+
+    fn add_handler(&mut self, start: usize, end: usize, target: usize, stack_depth: usize) {
+        self.current_chunk().handlers.push(Handler {
+            start,
+            end,
+            target,
+            stack_depth,
+        });
+    }
+
     fn while_statement(&mut self) {
         // while (condition) {statement}
-        let loop_start = self.chunk.code.len();
+        let loop_start = self.current_chunk().code.len();
 
         self.consume(LeftParen, "Expect '(' after 'while'.");
         self.expression();
@@ -176,11 +637,13 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
         let condition_jump = emit_jump!(self, JumpIfFalse);
         self.emit_op(OpCode::Pop);
 
+        self.begin_loop(loop_start);
         self.statement();
         self.emit_loop(loop_start);
 
         self.patch_jump(condition_jump);
         self.emit_op(OpCode::Pop);
+        self.end_loop();
     }
 
     fn for_statement(&mut self) {
@@ -188,6 +651,12 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
 
         // ------------------ 1. Initialization
         self.consume(LeftParen, "Expect '(' after 'for'.");
+        if self.check(Identifier) && self.scanner.clone().scan_token().ty == In {
+            self.for_in_statement();
+            self.end_scope();
+            return;
+        }
+
         // we
         if self.match_(Semicolon) {
             // No initialization
@@ -197,7 +666,7 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
             self.expression_statement();
         }
 
-        let expr_loc = self.chunk.code.len();
+        let expr_loc = self.current_chunk().code.len();
         let mut end_jump = None;
 
         // ------------------ 2. Condition
@@ -205,83 +674,945 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
             self.expression();
             self.consume(Semicolon, "Expect ';' after condition.");
 
-            end_jump = Some(emit_jump!(self, JumpIfFalse));
-            self.emit_op(OpCode::Pop);
+            end_jump = Some(emit_jump!(self, JumpIfFalse));
+            self.emit_op(OpCode::Pop);
+        }
+
+        // skip-increment
+        let loop_body_jump = emit_jump!(self, Jump);
+        let increment_loc = self.current_chunk().code.len();
+
+        // ------------------ 3. Increment
+        if !self.match_(RightParen) {
+            self.expression();
+            self.emit_op(OpCode::Pop);
+            self.consume(RightParen, "Expect ')' after increment clause.");
+        }
+
+        // Go back to condition
+        self.emit_loop(expr_loc);
+
+        self.patch_jump(loop_body_jump);
+
+        // `continue` still has to run the increment clause
+        self.begin_loop(increment_loc);
+        self.statement();
+
+        self.emit_loop(increment_loc);
+
+        if let Some(x) = end_jump {
+            self.patch_jump(x);
+            self.emit_op(OpCode::Pop);
+        }
+        self.end_loop();
+
+        self.end_scope();
+    }
+
+    fn for_in_statement(&mut self) {
+        // for (name in iterable) statement
+        self.consume(Identifier, "Expect loop variable name.");
+        let name = self.previous.unwrap();
+        self.consume(In, "Expect 'in' after loop variable.");
+        self.expression();
+        self.consume(RightParen, "Expect ')' after for-in iterable.");
+
+        // The iterator stays in a hidden local for the whole loop
+        self.emit_op(OpCode::IterInit);
+        self.add_local(Self::synthetic_token(""));
+        self.mark_initialized();
+        let iterator = (self.state().locals.len() - 1) as u8;
+
+        let loop_start = self.current_chunk().code.len();
+        self.emit_op(OpCode::IterHasNext {
+            stack_idx: iterator,
+        });
+        let exit_jump = emit_jump!(self, JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+
+        self.begin_loop(loop_start);
+
+        // Each iteration gets a fresh variable, closures capture the value they saw
+        self.begin_scope();
+        self.emit_op(OpCode::IterNext {
+            stack_idx: iterator,
+        });
+        self.add_local(name);
+        self.mark_initialized();
+        self.statement();
+        self.end_scope();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_op(OpCode::Pop);
+        self.end_loop();
+    }
+
+    fn match_statement(&mut self) {
+        // match (value) { case 1, 2 => statement ... default => statement }
+        //
+        // Integer and string literal cases are found through a jump table, any other
+        // case is compared in order. After the first compared case, literals are
+        // compared too, so the first matching case always wins.
+        self.consume(LeftParen, "Expect '(' after 'match'.");
+        self.expression();
+        self.consume(RightParen, "Expect ')' after match value.");
+        self.consume(LeftBrace, "Expect '{' before match cases.");
+
+        self.begin_scope();
+        self.add_local(Self::synthetic_token(""));
+        self.mark_initialized();
+        let subject = (self.state().locals.len() - 1) as u8;
+
+        // Reserve the table now, case bodies may contain their own matches
+        let table = self.current_chunk().jump_tables.len();
+        if table == U8_COUNT {
+            self.error("Too many match statements in one function.");
+        }
+        self.current_chunk()
+            .jump_tables
+            .push(JumpTable::new(vec![], 0));
+        self.emit_op(OpCode::MatchJump { table: table as u8 });
+
+        let mut cases = vec![];
+        let mut otherwise = None;
+        let mut end_jumps = vec![];
+        while self.match_(Case) {
+            let mut literals = vec![];
+            let mut matched_jumps = vec![];
+            loop {
+                let literal = if otherwise.is_none() {
+                    self.match_case_literal()
+                } else {
+                    None
+                };
+                match literal {
+                    Some(value) => literals.push(value),
+                    None => {
+                        otherwise.get_or_insert(self.current_chunk().code.len());
+                        self.emit_op(OpCode::GetLocal { stack_idx: subject });
+                        self.case_value = true;
+                        self.expression();
+                        self.case_value = false;
+                        self.emit_op(OpCode::Equal);
+                        let skip = emit_jump!(self, JumpIfFalse);
+                        self.emit_op(OpCode::Pop);
+                        matched_jumps.push(emit_jump!(self, Jump));
+                        self.patch_jump(skip);
+                        self.emit_op(OpCode::Pop);
+                    }
+                }
+                if !self.match_(Comma) {
+                    break;
+                }
+            }
+            self.consume(Arrow, "Expect '=>' after case values.");
+            let next_case = emit_jump!(self, Jump);
+
+            let body = self.current_chunk().code.len();
+            for jump in matched_jumps {
+                self.patch_jump(jump);
+            }
+            cases.extend(literals.into_iter().map(|value| (value, body)));
+            self.statement();
+            end_jumps.push(emit_jump!(self, Jump));
+            self.patch_jump(next_case);
+        }
+
+        let otherwise = otherwise.unwrap_or(self.current_chunk().code.len());
+        if self.match_(Default) {
+            self.consume(Arrow, "Expect '=>' after 'default'.");
+            self.statement();
+        }
+        self.consume(RightBrace, "Expect '}' after match cases.");
+
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+        if table < U8_COUNT {
+            self.current_chunk().jump_tables[table] = JumpTable::new(cases, otherwise);
+        }
+    }
+
+    /// Consume a case value that can go in a jump table, an integer or string literal on its own
+    fn match_case_literal(&mut self) -> Option<Value> {
+        let token = self.current.unwrap();
+        let next = self.scanner.clone().scan_token().ty;
+        if next != Comma && next != Arrow {
+            return None;
+        }
+        let value = match token.ty {
+            Number => {
+                let value: f64 = token.string.parse().unwrap();
+                if value.fract() != 0.0 {
+                    return None;
+                }
+                Value::Number(value)
+            }
+            TokenType::String => {
+                let raw = token.string;
+                Value::Object(self.allocate_string(unescape(&raw[1..raw.len() - 1])))
+            }
+            _ => return None,
+        };
+        self.advance();
+        Some(value)
+    }
+
+    fn begin_loop(&mut self, continue_target: usize) {
+        let scope_depth = self.state().scope_depth;
+        self.state_mut().loops.push(LoopState {
+            continue_target,
+            scope_depth,
+            break_jumps: vec![],
+        });
+    }
+
+    /// Point all `break`s of innermost loop to the current location
+    fn end_loop(&mut self) {
+        let state = self.state_mut().loops.pop().unwrap();
+        for jump in state.break_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn break_statement(&mut self) {
+        if self.state().loops.is_empty() {
+            self.error("Can't use 'break' outside of a loop.");
+        }
+        self.consume(Semicolon, "Expect ';' after 'break'.");
+        if self.state().loops.is_empty() {
+            return;
+        }
+        self.emit_break();
+    }
+
+    fn emit_break(&mut self) {
+        if self.leaves_try() {
+            self.exit_try(TryExit::Break);
+            return;
+        }
+        self.discard_loop_locals();
+        let jump = emit_jump!(self, Jump);
+        self.state_mut()
+            .loops
+            .last_mut()
+            .unwrap()
+            .break_jumps
+            .push(jump);
+    }
+
+    fn continue_statement(&mut self) {
+        if self.state().loops.is_empty() {
+            self.error("Can't use 'continue' outside of a loop.");
+        }
+        self.consume(Semicolon, "Expect ';' after 'continue'.");
+        if self.state().loops.is_empty() {
+            return;
+        }
+        self.emit_continue();
+    }
+
+    fn emit_continue(&mut self) {
+        if self.leaves_try() {
+            self.exit_try(TryExit::Continue);
+            return;
+        }
+        self.discard_loop_locals();
+        let target = self.state().loops.last().unwrap().continue_target;
+        self.emit_loop(target);
+    }
+
+    /// Whether `break` and `continue` of the innermost loop jump out of a `try`
+    fn leaves_try(&mut self) -> bool {
+        match self.state().tries.last() {
+            Some(state) => state.loop_count == self.state().loops.len(),
+            None => false,
+        }
+    }
+
+    /// Pop locals declared inside the innermost loop body without forgetting them,
+    /// the code after a `break` or `continue` is still in their scope.
+    fn discard_loop_locals(&mut self) {
+        let loop_depth = self.state().loops.last().unwrap().scope_depth;
+        let ops: Vec<_> = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth > loop_depth))
+            .map(|local| {
+                if local.is_captured {
+                    OpCode::CloseUpvalue
+                } else {
+                    OpCode::Pop
+                }
+            })
+            .collect();
+        for op in ops {
+            self.emit_op(op);
+        }
+    }
+
+    fn define_variable(&mut self, location: u8) {
+        // local variable is referenced by index in stack instead of name
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_op(OpCode::DefineGlobal { location });
+    }
+
+    fn and_(&mut self, _can_assing: bool) {
+        let end_jump = emit_jump!(self, JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.parse_precedence(Precedence::And);
+        self.patch_jump(end_jump);
+    }
+
+    fn or_(&mut self, _can_assing: bool) {
+        // 1. true or X => true
+        // 2. false or X => X
+
+        let else_jump = emit_jump!(self, JumpIfFalse);
+        let end_jump = emit_jump!(self, Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_op(OpCode::Pop); // Case 2
+        self.parse_precedence(Precedence::Or);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn conditional(&mut self, _can_assign: bool) {
+        // Right associative, a ? b : c ? d : e is a ? b : (c ? d : e)
+        let else_jump = emit_jump!(self, JumpIfFalse);
+        self.emit_op(OpCode::Pop);
+        self.parse_precedence(Precedence::Conditional);
+        let end_jump = emit_jump!(self, Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_op(OpCode::Pop);
+        self.consume(
+            Colon,
+            "Expect ':' after then branch of conditional expression.",
+        );
+        self.parse_precedence(Precedence::Conditional);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn coalesce(&mut self, _can_assign: bool) {
+        // 1. X ?? Y => X, if X isn't nil
+        // 2. nil ?? Y => Y
+        let end_jump = emit_jump!(self, JumpIfNotNil);
+        self.emit_op(OpCode::Pop); // Case 2
+        self.parse_precedence(Precedence::Coalesce);
+        self.patch_jump(end_jump);
+    }
+
+    fn var_declaration(&mut self) {
+        if self.check(LeftBracket) || self.check(LeftBrace) {
+            self.destructuring_declaration();
+            return;
+        }
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_(Equal) {
+            self.expression();
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.consume(Semicolon, "Expect `;` after variable declaration.");
+        self.define_variable(global);
+    }
+
+    fn destructuring_declaration(&mut self) -> Pattern<'a> {
+        // var [a, {b, c}] = value;
+        let pattern = self.pattern();
+        self.consume(Equal, "Expect '=' after destructuring pattern.");
+        self.expression();
+        self.consume(Semicolon, "Expect `;` after variable declaration.");
+        if self.state().scope_depth > 0 {
+            self.declare_pattern_locals(&pattern);
+        } else {
+            self.store_pattern(&pattern, true);
+        }
+        pattern
+    }
+
+    fn pattern_assignment(&mut self) {
+        // [a, b] = value;
+        let pattern = self.pattern();
+        self.consume(Equal, "Expect '=' after destructuring pattern.");
+        self.expression();
+        self.consume(Semicolon, "Expect ';' after value.");
+        self.store_pattern(&pattern, false);
+    }
+
+    /// Whether the `[` at current token starts a `[a, b] = value;` statement
+    fn is_pattern_assignment(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut depth = 1;
+        while depth > 0 {
+            match scanner.scan_token().ty {
+                LeftBracket => depth += 1,
+                RightBracket => depth -= 1,
+                Eof => return false,
+                _ => (),
+            }
+        }
+        scanner.scan_token().ty == Equal
+    }
+
+    fn pattern(&mut self) -> Pattern<'a> {
+        if self.match_(LeftBracket) {
+            let mut items = vec![];
+            if !self.check(RightBracket) {
+                loop {
+                    if items.len() == u8::MAX as usize {
+                        self.error_at_current("Can't have more than 255 elements in a pattern.");
+                    }
+                    items.push(self.pattern());
+                    if !self.match_(Comma) {
+                        break;
+                    }
+                }
+            }
+            self.consume(RightBracket, "Expect ']' after list pattern.");
+            return Pattern::List(items);
+        }
+        if self.match_(LeftBrace) {
+            let mut fields = vec![];
+            if !self.check(RightBrace) {
+                loop {
+                    self.consume(Identifier, "Expect field name in pattern.");
+                    let name = self.previous.unwrap();
+                    let target = if self.match_(Colon) {
+                        self.pattern()
+                    } else {
+                        Pattern::Name(name)
+                    };
+                    fields.push((name, target));
+                    if !self.match_(Comma) {
+                        break;
+                    }
+                }
+            }
+            self.consume(RightBrace, "Expect '}' after object pattern.");
+            return Pattern::Object(fields);
+        }
+        self.consume(Identifier, "Expect variable name in pattern.");
+        Pattern::Name(self.previous.unwrap())
+    }
+
+    /// Bind the value on top of stack to `pattern`, each name becomes a new local.
+    /// Lists unpack into consecutive slots, other values get a hidden local.
+    fn declare_pattern_locals(&mut self, pattern: &Pattern<'a>) {
+        match pattern {
+            Pattern::Name(name) => {
+                self.declare_local(*name);
+                self.mark_initialized();
+            }
+            Pattern::List(items) => {
+                self.emit_op(OpCode::UnpackList {
+                    count: items.len() as u8,
+                });
+                // Nested patterns unpack from their slot once every element has one
+                for item in items {
+                    match item {
+                        Pattern::Name(name) => self.declare_local(*name),
+                        _ => self.add_local(Self::synthetic_token("")),
+                    }
+                    self.mark_initialized();
+                }
+                let first_slot = self.state().locals.len() - items.len();
+                for (i, item) in items.iter().enumerate() {
+                    if !matches!(item, Pattern::Name(_)) {
+                        let stack_idx = (first_slot + i) as u8;
+                        self.emit_op(OpCode::GetLocal { stack_idx });
+                        self.declare_pattern_locals(item);
+                    }
+                }
+            }
+            Pattern::Object(fields) => {
+                self.add_local(Self::synthetic_token(""));
+                self.mark_initialized();
+                let stack_idx = (self.state().locals.len() - 1) as u8;
+                for (field, target) in fields {
+                    let location = self.identifier_constant(*field);
+                    self.emit_ops(
+                        OpCode::GetLocal { stack_idx },
+                        OpCode::UnpackField { location },
+                    );
+                    self.declare_pattern_locals(target);
+                }
+            }
+        }
+    }
+
+    /// Bind the value on top of stack to `pattern` and pop it. Names are defined
+    /// as globals if `define` is set, otherwise they are assigned to.
+    fn store_pattern(&mut self, pattern: &Pattern<'a>, define: bool) {
+        match pattern {
+            Pattern::Name(name) if define => {
+                let location = self.identifier_constant(*name);
+                self.emit_op(OpCode::DefineGlobal { location });
+            }
+            Pattern::Name(name) => {
+                let target = self.resolve_named(*name);
+                self.check_assignable(target);
+                let set_op = self.set_op(target);
+                self.emit_ops(set_op, OpCode::Pop);
+            }
+            Pattern::List(items) => {
+                self.emit_op(OpCode::UnpackList {
+                    count: items.len() as u8,
+                });
+                // The last element ends up on top
+                for item in items.iter().rev() {
+                    self.store_pattern(item, define);
+                }
+            }
+            Pattern::Object(fields) => {
+                for (field, target) in fields {
+                    let location = self.identifier_constant(*field);
+                    self.emit_ops(
+                        OpCode::Dup { distance: 0 },
+                        OpCode::UnpackField { location },
+                    );
+                    self.store_pattern(target, define);
+                }
+                self.emit_op(OpCode::Pop);
+            }
+        }
+    }
+
+    fn const_declaration(&mut self) {
+        // const name = value;
+        let global = self.parse_variable("Expect constant name.");
+        if self.state().scope_depth > 0 {
+            self.state_mut().locals.last_mut().unwrap().is_const = true;
+        }
+
+        self.consume(Equal, "Expect '=' after constant name.");
+        let start = self.current_chunk().code.len();
+        self.expression();
+        self.consume(Semicolon, "Expect `;` after constant declaration.");
+
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        // A lone literal can be inlined wherever the constant is read
+        let code = &self.current_chunk().code;
+        let literal = match code[start..] {
+            [(OpCode::Constant { location }, _)] => {
+                Some(self.current_chunk().constants[location as usize].clone())
+            }
+            [(OpCode::True, _)] => Some(Value::Bool(true)),
+            [(OpCode::False, _)] => Some(Value::Bool(false)),
+            [(OpCode::Nil, _)] => Some(Value::Nil),
+            _ => None,
+        };
+        if let Some(value) = literal {
+            let name = self.current_chunk().constants[global as usize].clone();
+            self.const_globals.set(name, value);
+        }
+        self.emit_op(OpCode::DefineConstGlobal { location: global });
+    }
+
+    fn enum_declaration(&mut self) {
+        // enum Name { Variant(field, ...), Variant, ... }
+        //
+        // The whole enum is built here and stored as a constant, so variants
+        // of top-level enums can be resolved while compiling.
+        let global = self.parse_variable("Expect enum name.");
+        let name = self.previous.unwrap().string.to_string();
+        self.consume(LeftBrace, "Expect '{' before enum body.");
+
+        let mut variants = HashTable::new();
+        while !self.check(RightBrace) && !self.check(Eof) {
+            self.consume(Identifier, "Expect variant name.");
+            let variant_name = self.previous.unwrap().string;
+            let mut fields = vec![];
+            if self.match_(LeftParen) {
+                if !self.check(RightParen) {
+                    loop {
+                        if fields.len() == 255 {
+                            self.error_at_current("Can't have more than 255 fields.");
+                        }
+                        self.consume(Identifier, "Expect field name.");
+                        fields.push(self.previous.unwrap().string.to_string());
+                        if !self.match_(Comma) {
+                            break;
+                        }
+                    }
+                }
+                self.consume(RightParen, "Expect ')' after variant fields.");
+            }
+
+            let is_constructor = !fields.is_empty();
+            let mut variant = Rc::new(LoxObject::Variant {
+                enum_name: name.clone(),
+                name: variant_name.to_string(),
+                fields,
+            });
+            // A variant without fields is a value on its own
+            if !is_constructor {
+                variant = Rc::new(LoxObject::EnumValue {
+                    variant,
+                    values: vec![],
+                });
+            }
+            let key = Value::Object(self.allocate_string(variant_name.to_string()));
+            if !variants.set(key, Value::Object(variant)) {
+                self.error("Already a variant with this name in this enum.");
+            }
+            if !self.match_(Comma) {
+                break;
+            }
+        }
+        self.consume(RightBrace, "Expect '}' after enum body.");
+
+        let value = Value::Object(Rc::new(LoxObject::Enum { name, variants }));
+        self.emit_constant(value.clone());
+        if self.state().scope_depth > 0 {
+            self.state_mut().locals.last_mut().unwrap().is_const = true;
+            self.mark_initialized();
+            return;
+        }
+        let name = self.current_chunk().constants[global as usize].clone();
+        self.const_globals.set(name, value);
+        self.emit_op(OpCode::DefineConstGlobal { location: global });
+    }
+
+    fn import_declaration(&mut self) {
+        // import "path/to/module.lox" as name;
+        self.consume(TokenType::String, "Expect module path after 'import'.");
+        let path = self.previous.unwrap().string;
+        let path = self.allocate_string(unescape(&path[1..path.len() - 1]));
+        let location = self.make_constant(Value::Object(path));
+
+        self.consume(As, "Expect 'as' after module path.");
+        let global = self.parse_variable("Expect module name after 'as'.");
+        self.consume(Semicolon, "Expect ';' after import.");
+
+        self.emit_op(OpCode::Import { location });
+        self.define_variable(global);
+    }
+
+    fn export_declaration(&mut self) {
+        // export var/const/fun/class/enum name ...
+        if self.states.len() > 1 || self.state().scope_depth > 0 {
+            self.error("Can only export top-level declarations.");
+        }
+        // The name follows the declaration keyword, or the `*` of a generator
+        let mut scanner = self.scanner.clone();
+        let mut name = scanner.scan_token();
+        if self.check(Fun) && name.ty == Star {
+            name = scanner.scan_token();
+        }
+        let mut names = vec![name];
+
+        if self.match_(Var) {
+            if self.check(LeftBracket) || self.check(LeftBrace) {
+                // Every name bound by the pattern is exported
+                names.clear();
+                self.destructuring_declaration().names(&mut names);
+            } else {
+                self.var_declaration();
+            }
+        } else if self.match_(Const) {
+            self.const_declaration();
+        } else if self.match_(Fun) {
+            self.fun_declaration();
+        } else if self.match_(Class) {
+            self.class_declaration();
+        } else if self.match_(Enum) {
+            self.enum_declaration();
+        } else {
+            self.error_at_current("Expect declaration after 'export'.");
+            return;
+        }
+        for name in names {
+            let location = self.identifier_constant(name);
+            self.emit_op(OpCode::Export { location });
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        // class Name {}
+        self.consume(Identifier, "Expect class name.");
+        let class_name = self.previous.unwrap();
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        self.emit_op(OpCode::Class {
+            location: name_constant,
+        });
+        self.define_variable(name_constant);
+        self.classes.push(ClassState {
+            has_superclass: false,
+        });
+
+        // class Name < Superclass {}
+        if self.match_(Less) {
+            self.consume(Identifier, "Expect superclass name.");
+            self.variable(false);
+            if self.identifiers_equal(class_name, self.previous.unwrap()) {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // Every method closes over the superclass through this local,
+            // a new scope keeps sibling classes from clashing over the name
+            self.begin_scope();
+            self.add_local(Self::synthetic_token("super"));
+            self.define_variable(0);
+
+            self.named_variable(false, class_name);
+            self.emit_op(OpCode::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // Keep the class on stack while methods are being attached to it
+        self.named_variable(false, class_name);
+        self.consume(LeftBrace, "Expect '{' before class body.");
+        while !self.check(RightBrace) && !self.check(Eof) {
+            self.method();
+        }
+        self.consume(RightBrace, "Expect '}' after class body.");
+        self.emit_op(OpCode::Pop);
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self) {
+        // `get`, `set` and `static` only mean something in front of a name,
+        // they are fine as method names themselves
+        let next = self.scanner.clone().scan_token().ty;
+        if self.check(Identifier) && matches!(next, Identifier | Star) {
+            match self.current.unwrap().string {
+                "static" => {
+                    self.advance();
+                    self.static_member();
+                    return;
+                }
+                "get" if next == Identifier => {
+                    self.advance();
+                    self.accessor(FunctionType::Getter);
+                    return;
+                }
+                "set" if next == Identifier => {
+                    self.advance();
+                    self.accessor(FunctionType::Setter);
+                    return;
+                }
+                _ => {}
+            }
+        }
+
+        // *name() {...} declares a generator method
+        let generator = self.match_(Star);
+        self.consume(Identifier, "Expect method name.");
+        let name = self.previous.unwrap();
+        let location = self.identifier_constant(name);
+
+        let ty = if name.string == "init" {
+            if generator {
+                self.error("Can't make an initializer a generator.");
+            }
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(ty, name.string, generator);
+        self.emit_op(OpCode::Method { location });
+    }
+
+    fn accessor(&mut self, ty: FunctionType) {
+        // get name() {...} or set name(value) {...}
+        self.consume(Identifier, "Expect property name.");
+        let name = self.previous.unwrap();
+        let location = self.identifier_constant(name);
+        self.function(ty, name.string, false);
+        if ty == FunctionType::Getter {
+            self.emit_op(OpCode::Getter { location });
+        } else {
+            self.emit_op(OpCode::Setter { location });
         }
+    }
 
-        // skip-increment
-        let loop_body_jump = emit_jump!(self, Jump);
-        let increment_loc = self.chunk.code.len();
+    fn static_member(&mut self) {
+        // static name(a, b) {...} or static name = value;
+        let generator = self.match_(Star);
+        self.consume(Identifier, "Expect static member name.");
+        let name = self.previous.unwrap();
+        let location = self.identifier_constant(name);
 
-        // ------------------ 3. Increment
-        if !self.match_(RightParen) {
-            self.expression();
-            self.emit_op(OpCode::Pop);
-            self.consume(RightParen, "Expect ')' after increment clause.");
+        if generator || self.check(LeftParen) {
+            // `this` is the class itself in a static method
+            self.function(FunctionType::Method, name.string, generator);
+        } else {
+            if self.match_(Equal) {
+                self.expression();
+            } else {
+                self.emit_op(OpCode::Nil);
+            }
+            self.consume(Semicolon, "Expect ';' after static field.");
         }
+        self.emit_op(OpCode::StaticMember { location });
+    }
 
-        // Go back to condition
-        self.emit_loop(expr_loc);
-
-        self.patch_jump(loop_body_jump);
+    fn fun_declaration(&mut self) {
+        let generator = self.match_(Star);
+        let global = self.parse_variable("Expect function name.");
 
-        self.statement();
+        // A function can refer to itself in its body for recursion
+        self.mark_initialized();
+        let name = self.previous.unwrap().string;
+        self.function(FunctionType::Function, name, generator);
+        self.define_variable(global);
+    }
 
-        self.emit_loop(increment_loc);
+    fn function(&mut self, ty: FunctionType, name: &str, generator: bool) {
+        // fun name(a, b) {...}
+        self.states
+            .push(FunctionState::new(ty, Some(name.to_string())));
+        self.state_mut().generator = generator;
+        self.begin_scope();
 
-        if let Some(x) = end_jump {
-            self.patch_jump(x);
-            self.emit_op(OpCode::Pop);
+        self.consume(LeftParen, "Expect '(' after function name.");
+        self.parameters();
+        let state = self.state();
+        let params = state.arity + state.optional + state.variadic as usize;
+        match ty {
+            FunctionType::Getter if params != 0 => self.error("A getter can't have parameters."),
+            FunctionType::Setter if params != 1 || state.arity != 1 => {
+                self.error("A setter must have exactly one parameter.")
+            }
+            _ => {}
         }
+        self.consume(LeftBrace, "Expect '{' before function body.");
+        self.block();
 
-        self.end_scope();
+        self.end_function();
     }
 
-    fn define_variable(&mut self, location: u8) {
-        // local variable is referenced by index in stack instead of name
-        if self.scope_depth > 0 {
-            self.mark_initialized();
-            return;
+    /// Parameter list up to and including the closing `)`.
+    /// Defaults are evaluated by the function itself when it's called.
+    fn parameters(&mut self) {
+        // a, b = default, ...rest
+        if !self.check(RightParen) {
+            loop {
+                let index = self.state().arity + self.state().optional;
+                if index == 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                if self.state().variadic {
+                    self.error_at_current("Rest parameter must be last.");
+                }
+
+                if self.match_(DotDotDot) {
+                    self.state_mut().variadic = true;
+                    let constant = self.parse_variable("Expect parameter name.");
+                    self.define_variable(constant);
+                } else {
+                    let constant = self.parse_variable("Expect parameter name.");
+                    self.define_variable(constant);
+                    if self.match_(Equal) {
+                        self.state_mut().optional += 1;
+                        let slot = (self.state().locals.len() - 1) as u8;
+                        self.emit_op(OpCode::JumpIfArgPassed {
+                            index: index as u8,
+                            offset: 0,
+                        });
+                        let skip = self.current_chunk().code.len();
+                        self.expression();
+                        self.emit_ops(OpCode::SetLocal { stack_idx: slot }, OpCode::Pop);
+                        self.patch_jump(skip);
+                    } else if self.state().optional > 0 {
+                        self.error("Expect default value for parameter after one with a default.");
+                    } else {
+                        self.state_mut().arity += 1;
+                    }
+                }
+                if !self.match_(Comma) {
+                    break;
+                }
+            }
         }
-        self.emit_op(OpCode::DefineGlobal { location });
+        self.consume(RightParen, "Expect ')' after parameters.");
     }
 
-    fn and_(&mut self, _can_assing: bool) {
-        let end_jump = emit_jump!(self, JumpIfFalse);
-        self.emit_op(OpCode::Pop);
-        self.parse_precedence(Precedence::And);
-        self.patch_jump(end_jump);
+    /// Wrap up the innermost function and emit the closure creating it
+    fn end_function(&mut self) {
+        // No end_scope, the locals are discarded along with the call frame
+        let function = self.end_compiler();
+        let location = self.make_constant(Value::Object(Rc::new(function)));
+        self.emit_op(OpCode::Closure { location });
     }
 
-    fn or_(&mut self, _can_assing: bool) {
-        // 1. true or X => true
-        // 2. false or X => X
+    fn lambda(&mut self, _can_assign: bool) {
+        // fun (a, b) {...} or fun* (a, b) {...}
+        let generator = self.match_(Star);
+        self.function(FunctionType::Function, "lambda", generator);
+    }
 
-        let else_jump = emit_jump!(self, JumpIfFalse);
-        let end_jump = emit_jump!(self, Jump);
+    fn arrow_function(&mut self) {
+        // (a, b) => expression, the `(` is already consumed
+        self.states.push(FunctionState::new(
+            FunctionType::Function,
+            Some("lambda".to_string()),
+        ));
+        self.begin_scope();
 
-        self.patch_jump(else_jump);
-        self.emit_op(OpCode::Pop); // Case 2
-        self.parse_precedence(Precedence::Or);
+        self.parameters();
+        self.consume(Arrow, "Expect '=>' after parameters.");
+        self.expression();
+        self.emit_op(OpCode::Return);
 
-        self.patch_jump(end_jump);
+        self.end_function();
     }
 
-    fn var_declaration(&mut self) {
-        let global = self.parse_variable("Expect variable name.");
-
-        if self.match_(Equal) {
-            self.expression();
-        } else {
-            self.emit_op(OpCode::Nil);
+    /// Whether the `)` matching an already consumed `(` is followed by `=>`
+    fn is_arrow_function(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut token = self.current.unwrap();
+        let mut depth = 1;
+        loop {
+            match token.ty {
+                LeftParen => depth += 1,
+                RightParen if depth == 1 => break,
+                RightParen => depth -= 1,
+                Eof => return false,
+                _ => (),
+            }
+            token = scanner.scan_token();
         }
-        self.consume(Semicolon, "Expect `;` after variable declaration.");
-        self.define_variable(global);
+        scanner.scan_token().ty == Arrow
     }
 
     fn declaration(&mut self) {
-        if self.match_(Var) {
+        if self.match_(Class) {
+            self.class_declaration();
+        } else if self.check(Fun) && !self.is_lambda() {
+            self.advance();
+            self.fun_declaration();
+        } else if self.match_(Var) {
             self.var_declaration();
+        } else if self.match_(Const) {
+            self.const_declaration();
+        } else if self.match_(Enum) {
+            self.enum_declaration();
+        } else if self.match_(Import) {
+            self.import_declaration();
+        } else if self.match_(Export) {
+            self.export_declaration();
         } else {
             self.statement();
         }
@@ -298,6 +1629,16 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
         }
     }
 
+    /// Whether the current `fun` starts an expression statement with a lambda, `fun (` or `fun* (`
+    fn is_lambda(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut token = scanner.scan_token();
+        if token.ty == Star {
+            token = scanner.scan_token();
+        }
+        token.ty == LeftParen
+    }
+
     fn synchronize(&mut self) {
         self.panic_mode = false;
         while self.current.unwrap().ty != Eof {
@@ -305,7 +1646,8 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
                 return;
             }
             match self.current.unwrap().ty {
-                Class | Fun | Var | For | If | While | Print | Return => {
+                Class | Fun | Var | Const | Import | Export | For | If | While | Print | Return
+                | Throw | Try | Match | Enum | Yield => {
                     return;
                 }
                 _ => {}
@@ -319,14 +1661,30 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
             self.print_statement();
         } else if self.match_(If) {
             self.if_statement();
+        } else if self.match_(Return) {
+            self.return_statement();
+        } else if self.match_(Break) {
+            self.break_statement();
+        } else if self.match_(Continue) {
+            self.continue_statement();
         } else if self.match_(While) {
             self.while_statement();
         } else if self.match_(For) {
             self.for_statement();
+        } else if self.match_(Throw) {
+            self.throw_statement();
+        } else if self.match_(Yield) {
+            self.yield_statement();
+        } else if self.match_(Try) {
+            self.try_statement();
+        } else if self.match_(Match) {
+            self.match_statement();
         } else if self.match_(LeftBrace) {
             self.begin_scope();
             self.block();
             self.end_scope();
+        } else if self.check(LeftBracket) && self.is_pattern_assignment() {
+            self.pattern_assignment();
         } else {
             self.expression_statement();
         }
@@ -340,37 +1698,242 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
     fn string(&mut self, _can_assing: bool) {
         let prv = self.previous.unwrap().string;
 
-        // remove the quotes
-        let str = self.allocate_string(prv[1..prv.len() - 1].to_string());
+        // remove the quotes, or the `}` of a preceding interpolation
+        let str = self.allocate_string(unescape(&prv[1..prv.len() - 1]));
         self.emit_constant(Value::Object(str));
     }
 
+    fn interpolation(&mut self, _can_assign: bool) {
+        // "a ${b} c ${d} e" compiles to "a " + str(b) + " c " + str(d) + " e"
+        let mut first = true;
+        loop {
+            let prv = self.previous.unwrap().string;
+            // remove the quote or `}` and the trailing `${`
+            let str = self.allocate_string(unescape(&prv[1..prv.len() - 2]));
+            self.emit_constant(Value::Object(str));
+            if !first {
+                self.emit_op(OpCode::Add);
+            }
+            first = false;
+
+            self.expression();
+            self.emit_ops(OpCode::Stringify, OpCode::Add);
+
+            if !self.match_(Interpolation) {
+                break;
+            }
+        }
+        self.consume(TokenType::String, "Expect end of string interpolation.");
+        self.string(false);
+        self.emit_op(OpCode::Add);
+    }
+
     fn named_variable(&mut self, can_assign: bool, token: Token<'a>) {
+        let target = self.resolve_named(token);
+        self.assignment(target, can_assign);
+    }
+
+    /// Find where the variable `token` lives
+    fn resolve_named(&mut self, token: Token<'a>) -> AssignTarget<'a> {
         let get_op;
         let set_op;
-        let arg = self.resolve_local(token);
-        match arg {
-            None => {
-                let arg = self.identifier_constant(token);
-                get_op = OpCode::GetGlobal { location: arg };
-                set_op = OpCode::SetGlobal { location: arg };
-            }
-            Some(x) => {
-                get_op = OpCode::GetLocal { stack_idx: x };
-                set_op = OpCode::SetLocal { stack_idx: x };
+        let is_const;
+        let current = self.states.len() - 1;
+        if let Some(x) = self.resolve_local(current, token) {
+            get_op = OpCode::GetLocal { stack_idx: x };
+            set_op = OpCode::SetLocal { stack_idx: x };
+            is_const = self.state().locals[x as usize].is_const;
+        } else if let Some(index) = self.resolve_upvalue(current, token) {
+            get_op = OpCode::GetUpvalue { index };
+            set_op = OpCode::SetUpvalue { index };
+            is_const = self.is_const_upvalue(current, index);
+        } else {
+            let name = Value::Object(self.allocate_string(token.string.to_string()));
+            if let Some(value) = self.const_globals.get(&name).cloned() {
+                return AssignTarget::ConstGlobal {
+                    value: self.reuse_constant(value),
+                    name: token,
+                };
             }
+            let arg = self.identifier_constant(token);
+            get_op = OpCode::GetGlobal { location: arg };
+            set_op = OpCode::SetGlobal { location: arg };
+            // Const globals are checked by the VM
+            is_const = false;
+        }
+
+        AssignTarget::Named {
+            get: get_op,
+            set: set_op,
+            is_const,
         }
+    }
 
-        if can_assign && self.match_(Equal) {
+    /// Compile whatever assignment follows `target`, or just read it
+    fn assignment(&mut self, target: AssignTarget<'a>, can_assign: bool) {
+        let operands = target.operands();
+        if let Some(op) = self.take_prefix_increment() {
+            // ++x
+            self.check_assignable(target);
+            self.emit_target_get(target);
+            self.emit_constant(Value::Number(1.0));
+            let set_op = self.set_op(target);
+            self.emit_ops(op, set_op);
+        } else if can_assign && self.match_(Equal) {
+            self.check_assignable(target);
             self.expression();
+            let set_op = self.set_op(target);
             self.emit_op(set_op);
+        } else if let Some(op) = self.match_compound_assignment(can_assign) {
+            // x += value
+            self.check_assignable(target);
+            self.emit_target_get(target);
+            self.expression();
+            let set_op = self.set_op(target);
+            self.emit_ops(op, set_op);
+        } else if let Some(op) = self.match_postfix_increment() {
+            // x++, the old value is kept below the target operands
+            self.check_assignable(target);
+            self.emit_target_get(target);
+            self.emit_op(OpCode::Dup { distance: 0 });
+            if operands > 0 {
+                self.emit_op(OpCode::Bury {
+                    depth: operands + 1,
+                });
+            }
+            self.emit_constant(Value::Number(1.0));
+            let set_op = self.set_op(target);
+            self.emit_ops(op, set_op);
+            self.emit_op(OpCode::Pop);
         } else {
-            self.emit_op(get_op);
+            self.emit_op(target.get_op());
+        }
+    }
+
+    fn set_op(&mut self, target: AssignTarget<'a>) -> OpCode {
+        match target {
+            AssignTarget::Named { set, .. } => set,
+            // Const globals are checked by the VM
+            AssignTarget::ConstGlobal { name, .. } => OpCode::SetGlobal {
+                location: self.identifier_constant(name),
+            },
+            AssignTarget::Property { location } => OpCode::SetProperty { location },
+            AssignTarget::Index => OpCode::IndexSet,
+        }
+    }
+
+    fn check_assignable(&mut self, target: AssignTarget<'a>) {
+        if let AssignTarget::Named { is_const: true, .. } = target {
+            self.error("Can't assign to a constant.");
+        }
+    }
+
+    /// Read the target while keeping its operands around for the set
+    fn emit_target_get(&mut self, target: AssignTarget<'a>) {
+        let operands = target.operands();
+        for _ in 0..operands {
+            self.emit_op(OpCode::Dup {
+                distance: operands - 1,
+            });
+        }
+        self.emit_op(target.get_op());
+    }
+
+    /// A pending prefix `++`/`--` applies to the target just parsed if the target
+    /// is at the prefix's own level and isn't followed by more of the postfix chain
+    fn take_prefix_increment(&mut self) -> Option<OpCode> {
+        let (op, depth) = self.prefix_increment?;
+        if depth != self.expression_depth
+            || self.check(Dot)
+            || self.check(LeftBracket)
+            || self.check(LeftParen)
+        {
+            return None;
+        }
+        self.prefix_increment = None;
+        Some(op)
+    }
+
+    fn match_compound_assignment(&mut self, can_assign: bool) -> Option<OpCode> {
+        if !can_assign {
+            return None;
+        }
+        let op = match self.current.unwrap().ty {
+            PlusEqual => OpCode::Add,
+            MinusEqual => OpCode::Subtract,
+            StarEqual => OpCode::Multiply,
+            SlashEqual => OpCode::Divide,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
+    fn match_postfix_increment(&mut self) -> Option<OpCode> {
+        let op = match self.current.unwrap().ty {
+            PlusPlus => OpCode::Add,
+            MinusMinus => OpCode::Subtract,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
+    fn prefix_increment(&mut self, _can_assign: bool) {
+        let op = match self.previous.unwrap().ty {
+            PlusPlus => OpCode::Add,
+            _ => OpCode::Subtract,
+        };
+        // An enclosing `++` is still waiting for its own target, as in `++xs[++i]`
+        let enclosing = self
+            .prefix_increment
+            .replace((op, self.expression_depth + 1));
+        self.parse_precedence(Precedence::Unary);
+        if std::mem::replace(&mut self.prefix_increment, enclosing).is_some() {
+            self.error("Invalid Assignment Target.");
         }
     }
 
     fn variable(&mut self, can_assign: bool) {
-        self.named_variable(can_assign, self.previous.unwrap());
+        let token = self.previous.unwrap();
+        if self.check(Dot) {
+            if let Some(enum_) = self.known_enum(token) {
+                self.enum_variant(&enum_);
+                return;
+            }
+        }
+        self.named_variable(can_assign, token);
+    }
+
+    /// Top-level enum named `token`, unless a local variable shadows it
+    fn known_enum(&mut self, token: Token<'a>) -> Option<Rc<LoxObject>> {
+        let name = Value::Object(self.allocate_string(token.string.to_string()));
+        let value = self.const_globals.get(&name).cloned()?;
+        let enum_ = value.as_object().filter(|x| x.as_enum().is_some())?.clone();
+
+        let current = self.states.len() - 1;
+        if self.resolve_local(current, token).is_some()
+            || self.resolve_upvalue(current, token).is_some()
+        {
+            return None;
+        }
+        Some(enum_)
+    }
+
+    /// `Enum.Variant` on a known enum, a missing variant is a compile error
+    fn enum_variant(&mut self, enum_: &LoxObject) {
+        self.consume(Dot, "Expect '.' after enum name.");
+        self.consume(Identifier, "Expect property name after '.'.");
+        let token = self.previous.unwrap();
+        let (enum_name, variants) = enum_.as_enum().unwrap();
+        let name = Value::Object(self.allocate_string(token.string.to_string()));
+        match variants.get_ref(&name) {
+            Some(variant) => self.emit_constant(variant.clone()),
+            None => self.error(&format!(
+                "Enum '{}' has no variant '{}'.",
+                enum_name, token.string
+            )),
+        }
     }
 
     fn allocate_string(&mut self, val: std::string::String) -> Rc<LoxObject> {
@@ -380,13 +1943,193 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
             return x;
         }
         let str = Rc::new(lox_str);
-        self.strings.set(str.clone(), Value::Nil);
+        self.strings.set(Value::Object(str.clone()), Value::Nil);
         return str;
     }
 
     fn grouping(&mut self, _can_assing: bool) {
+        // Inside the parentheses, `=>` can't be the one of a match case anymore
+        let case_value = std::mem::replace(&mut self.case_value, false);
+        if !case_value && self.is_arrow_function() {
+            self.arrow_function();
+        } else {
+            self.expression();
+            self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        }
+        self.case_value = case_value;
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let case_value = std::mem::replace(&mut self.case_value, false);
+        match self.argument_list() {
+            (arg_count, false) => self.emit_op(OpCode::Call { arg_count }),
+            (segments, true) => self.emit_op(OpCode::CallSpread { segments }),
+        }
+        self.case_value = case_value;
+    }
+
+    /// Returns the argument count, and whether a `...list` argument was spread.
+    /// With a spread, arguments are collected into lists and their count is returned instead.
+    fn argument_list(&mut self) -> (u8, bool) {
+        let mut arg_count: usize = 0;
+        let mut has_spread = false;
+        let mut segments: usize = 0;
+        // Plain arguments since the last spread
+        let mut pending: usize = 0;
+        if !self.check(RightParen) {
+            loop {
+                if arg_count == 255 {
+                    self.error_at_current("Can't have more than 255 arguments.");
+                }
+                if self.match_(DotDotDot) {
+                    if pending > 0 {
+                        self.emit_op(OpCode::BuildList {
+                            count: pending as u8,
+                        });
+                        segments += 1;
+                        pending = 0;
+                    }
+                    self.expression();
+                    has_spread = true;
+                    segments += 1;
+                } else {
+                    self.expression();
+                    pending += 1;
+                }
+                arg_count += 1;
+                if !self.match_(Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(RightParen, "Expect ')' after arguments.");
+
+        if !has_spread {
+            return (arg_count as u8, false);
+        }
+        if pending > 0 {
+            self.emit_op(OpCode::BuildList {
+                count: pending as u8,
+            });
+            segments += 1;
+        }
+        (segments as u8, true)
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(Identifier, "Expect property name after '.'.");
+        let location = self.identifier_constant(self.previous.unwrap());
+
+        if self.match_(LeftParen) {
+            // Calling a method right away doesn't need a bound method
+            match self.argument_list() {
+                (arg_count, false) => self.emit_op(OpCode::Invoke {
+                    location,
+                    arg_count,
+                }),
+                (segments, true) => self.emit_op(OpCode::InvokeSpread { location, segments }),
+            }
+        } else {
+            self.assignment(AssignTarget::Property { location }, can_assign);
+        }
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            _ => {}
+        }
+
+        self.consume(Dot, "Expect '.' after 'super'.");
+        self.consume(Identifier, "Expect superclass method name.");
+        let location = self.identifier_constant(self.previous.unwrap());
+
+        self.named_variable(false, Self::synthetic_token("this"));
+        if self.match_(LeftParen) {
+            let (arg_count, has_spread) = self.argument_list();
+            self.named_variable(false, Self::synthetic_token("super"));
+            if has_spread {
+                self.emit_op(OpCode::SuperInvokeSpread {
+                    location,
+                    segments: arg_count,
+                });
+            } else {
+                self.emit_op(OpCode::SuperInvoke {
+                    location,
+                    arg_count,
+                });
+            }
+        } else {
+            self.named_variable(false, Self::synthetic_token("super"));
+            self.emit_op(OpCode::GetSuper { location });
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        // `this` can never be assigned to
+        self.variable(false);
+    }
+
+    fn list(&mut self, _can_assign: bool) {
+        // [a, b, c]
+        let mut count: usize = 0;
+        if !self.check(RightBracket) {
+            loop {
+                // Allow a trailing comma
+                if self.check(RightBracket) {
+                    break;
+                }
+                self.expression();
+                if count == 255 {
+                    self.error("Can't have more than 255 elements in a list literal.");
+                }
+                count += 1;
+                if !self.match_(Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(RightBracket, "Expect ']' after list elements.");
+        self.emit_op(OpCode::BuildList { count: count as u8 });
+    }
+
+    fn map(&mut self, _can_assign: bool) {
+        // {key: value, ...}
+        let mut count: usize = 0;
+        if !self.check(RightBrace) {
+            loop {
+                // Allow a trailing comma
+                if self.check(RightBrace) {
+                    break;
+                }
+                self.expression();
+                self.consume(Colon, "Expect ':' after map key.");
+                self.expression();
+                if count == 255 {
+                    self.error("Can't have more than 255 entries in a map literal.");
+                }
+                count += 1;
+                if !self.match_(Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(RightBrace, "Expect '}' after map entries.");
+        self.emit_op(OpCode::BuildMap { count: count as u8 });
+    }
+
+    fn subscript(&mut self, can_assign: bool) {
         self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        self.consume(RightBracket, "Expect ']' after index.");
+
+        self.assignment(AssignTarget::Index, can_assign);
     }
 
     fn unary(&mut self, _can_assing: bool) {
@@ -395,13 +2138,19 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
         match operator {
             TokenType::Minus => self.emit_op(OpCode::Negate),
             TokenType::Bang => self.emit_op(OpCode::Not),
+            TokenType::Tilde => self.emit_op(OpCode::BitNot),
             _ => return,
         }
     }
     fn binary(&mut self, _can_assing: bool) {
         let operator = self.previous.as_ref().unwrap().ty;
         let rule = Self::get_rule(operator);
-        self.parse_precedence(rule.precedence.get_next());
+        if operator == StarStar {
+            // Right associative, 2 ** 3 ** 2 is 2 ** (3 ** 2)
+            self.parse_precedence(rule.precedence);
+        } else {
+            self.parse_precedence(rule.precedence.get_next());
+        }
         match operator {
             BangEqual => self.emit_ops(OpCode::Equal, OpCode::Not),
             EqualEqual => self.emit_op(OpCode::Equal),
@@ -414,6 +2163,18 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
             TokenType::Minus => self.emit_op(OpCode::Subtract),
             TokenType::Star => self.emit_op(OpCode::Multiply),
             TokenType::Slash => self.emit_op(OpCode::Divide),
+            Percent => self.emit_op(OpCode::Modulo),
+            StarStar => self.emit_op(OpCode::Power),
+            TildeSlash => self.emit_op(OpCode::IntegerDivide),
+            Ampersand => self.emit_op(OpCode::BitAnd),
+            Pipe => self.emit_op(OpCode::BitOr),
+            Caret => self.emit_op(OpCode::BitXor),
+            LessLess => self.emit_op(OpCode::ShiftLeft),
+            GreaterGreater => self.emit_op(OpCode::ShiftRight),
+            DotDot => self.emit_op(OpCode::BuildRange { inclusive: false }),
+            DotDotEqual => self.emit_op(OpCode::BuildRange { inclusive: true }),
+            In => self.emit_op(OpCode::Contains),
+            Is => self.emit_op(OpCode::IsVariant),
             _ => unreachable!(),
         }
     }
@@ -428,11 +2189,13 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
+        self.expression_depth += 1;
         self.advance();
         let rule = Self::get_rule(self.previous.as_ref().unwrap().ty);
         let prefix_fn = match rule.prefix {
             None => {
                 self.error("Expect expression.");
+                self.expression_depth -= 1;
                 return;
             }
             Some(x) => x,
@@ -446,9 +2209,14 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
             infix_rule.unwrap()(self, can_assign);
         }
 
-        if can_assign && self.match_(Equal) {
+        if can_assign
+            && (self.match_(Equal)
+                || self.match_compound_assignment(can_assign).is_some()
+                || self.match_postfix_increment().is_some())
+        {
             self.error("Invalid Assignment Target.");
         }
+        self.expression_depth -= 1;
     }
 
     fn identifier_constant(&mut self, token: Token<'a>) -> u8 {
@@ -456,41 +2224,108 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
         self.make_constant(Value::Object(string))
     }
 
+    /// Identifier that doesn't appear in the source, but the compiler needs to resolve
+    fn synthetic_token(name: &'static str) -> Token<'a> {
+        Token {
+            ty: Identifier,
+            string: name,
+            line: 0,
+        }
+    }
+
     fn identifiers_equal(&self, a: Token<'a>, b: Token<'a>) -> bool {
         return a.string == b.string;
     }
 
-    fn resolve_local(&mut self, token: Token<'a>) -> Option<u8> {
-        for (i, value) in self.locals.iter().enumerate().rev() {
-            if self.identifiers_equal(value.name, token) {
-                if value.depth.is_none() {
-                    self.error("Can't read local variable in its own initializer.");
-                }
-                return Some(i as u8);
-            }
+    /// Resolve `token` among the locals of function at `state_idx` in `self.states`
+    fn resolve_local(&mut self, state_idx: usize, token: Token<'a>) -> Option<u8> {
+        let found = self.states[state_idx]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, value)| self.identifiers_equal(value.name, token))
+            .map(|(i, value)| (i, value.depth.is_none()));
+
+        let (i, is_uninitialized) = found?;
+        if is_uninitialized {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        Some(i as u8)
+    }
+
+    /// Resolve `token` as a variable of some enclosing function,
+    /// threading the upvalue through every function in between.
+    fn resolve_upvalue(&mut self, state_idx: usize, token: Token<'a>) -> Option<u8> {
+        if state_idx == 0 {
+            // Only globals remain outside the script
+            return None;
+        }
+        let enclosing = state_idx - 1;
+
+        if let Some(local) = self.resolve_local(enclosing, token) {
+            self.states[enclosing].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(state_idx, local, true));
+        }
+
+        let upvalue = self.resolve_upvalue(enclosing, token)?;
+        Some(self.add_upvalue(state_idx, upvalue, false))
+    }
+
+    /// Follow upvalue `index` of `state_idx` to the local it captures
+    fn is_const_upvalue(&self, state_idx: usize, index: u8) -> bool {
+        let capture = self.states[state_idx].upvalues[index as usize];
+        if capture.is_local {
+            return self.states[state_idx - 1].locals[capture.index as usize].is_const;
+        }
+        self.is_const_upvalue(state_idx - 1, capture.index)
+    }
+
+    fn add_upvalue(&mut self, state_idx: usize, index: u8, is_local: bool) -> u8 {
+        let upvalues = &self.states[state_idx].upvalues;
+        if let Some(existing) = upvalues
+            .iter()
+            .position(|x| x.index == index && x.is_local == is_local)
+        {
+            return existing as u8;
+        }
+
+        if upvalues.len() == U8_COUNT {
+            self.error("Too many closure variables in function.");
+            return 0;
         }
-        None
+        self.states[state_idx]
+            .upvalues
+            .push(Capture { index, is_local });
+        (self.states[state_idx].upvalues.len() - 1) as u8
     }
 
     fn add_local(&mut self, token: Token<'a>) {
-        if self.locals.len() == U8_COUNT {
+        if self.state().locals.len() == U8_COUNT {
             self.error("Too many local variables in function.");
             return;
         }
-        self.locals.push(Local {
+        self.state_mut().locals.push(Local {
             name: token,
             depth: None,
+            is_captured: false,
+            is_const: false,
         });
     }
 
     fn declare_variable(&mut self) {
-        if self.scope_depth == 0 {
+        if self.state().scope_depth == 0 {
             return;
         }
         let val = *self.previous.as_ref().unwrap();
-        for i in (0..self.locals.len()).rev() {
-            let local = &self.locals[i];
-            if local.depth.is_some() && local.depth.unwrap() < self.scope_depth {
+        self.declare_local(val);
+    }
+
+    /// Add a local named `val`, unless the current scope already has one
+    fn declare_local(&mut self, val: Token<'a>) {
+        for i in (0..self.state().locals.len()).rev() {
+            let local = &self.state().locals[i];
+            if local.depth.is_some() && local.depth.unwrap() < self.state().scope_depth {
                 break;
             }
             if self.identifiers_equal(val, local.name) {
@@ -503,14 +2338,18 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
     fn parse_variable(&mut self, msg: &str) -> u8 {
         self.consume(Identifier, msg);
         self.declare_variable();
-        if self.scope_depth > 0 {
+        if self.state().scope_depth > 0 {
             return 0;
         };
         self.identifier_constant(self.previous.unwrap())
     }
 
     fn mark_initialized(&mut self) {
-        self.locals.last_mut().unwrap().depth = Some(self.scope_depth);
+        if self.state().scope_depth == 0 {
+            return;
+        }
+        let depth = self.state().scope_depth;
+        self.state_mut().locals.last_mut().unwrap().depth = Some(depth);
     }
 
     fn advance(&mut self) {
@@ -548,34 +2387,50 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
         }
     }
 
-    fn end_compiler(&mut self) {
-        self.emit_op(OpCode::Return);
+    /// Finish the innermost function and hand back its function object
+    fn end_compiler(&mut self) -> LoxObject {
+        self.emit_return();
+        let state = self.states.pop().unwrap();
         #[cfg(feature = "trace_execution")]
         {
             if !self.had_error {
-                disassemble_chunk(&self.chunk, "code");
+                disassemble_chunk(&state.chunk, state.name.as_deref().unwrap_or("<script>"));
             }
         }
+        LoxObject::Function {
+            name: state.name,
+            arity: state.arity,
+            chunk: state.chunk,
+            upvalues: state.upvalues,
+            optional: state.optional,
+            variadic: state.variadic,
+            generator: state.generator,
+        }
     }
 
     fn begin_scope(&mut self) {
-        self.scope_depth += 1;
+        self.state_mut().scope_depth += 1;
     }
 
     fn end_scope(&mut self) {
-        self.scope_depth -= 1;
+        self.state_mut().scope_depth -= 1;
 
-        while !self.locals.is_empty()
-            && self.locals.last().unwrap().depth.is_some()
-            && self.locals.last().unwrap().depth.unwrap() > self.scope_depth
+        while !self.state().locals.is_empty()
+            && self.state().locals.last().unwrap().depth.is_some()
+            && self.state().locals.last().unwrap().depth.unwrap() > self.state().scope_depth
         {
-            self.emit_op(OpCode::Pop);
-            self.locals.pop();
+            if self.state().locals.last().unwrap().is_captured {
+                self.emit_op(OpCode::CloseUpvalue);
+            } else {
+                self.emit_op(OpCode::Pop);
+            }
+            self.state_mut().locals.pop();
         }
     }
 
     fn emit_op(&mut self, op: OpCode) {
-        self.chunk.write(op, self.previous.unwrap().line);
+        let line = self.previous.unwrap().line;
+        self.current_chunk().write(op, line);
     }
 
     fn emit_ops(&mut self, op1: OpCode, op2: OpCode) {
@@ -583,8 +2438,30 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
         self.emit_op(op2);
     }
 
+    fn emit_return(&mut self) {
+        if self.state().ty == FunctionType::Initializer {
+            // Initializers always hand back the instance
+            self.emit_op(OpCode::GetLocal { stack_idx: 0 });
+        } else if self.state().ty == FunctionType::Setter {
+            // Setters hand back the assigned value, it's what the assignment evaluates to
+            self.emit_op(OpCode::GetLocal { stack_idx: 1 });
+        } else {
+            self.emit_op(OpCode::Nil);
+        }
+        self.emit_return_value();
+    }
+
+    /// Return the value on top of stack, running enclosing `finally` blocks first
+    fn emit_return_value(&mut self) {
+        if self.state().tries.is_empty() {
+            self.emit_op(OpCode::Return);
+        } else {
+            self.exit_try(TryExit::Return);
+        }
+    }
+
     fn emit_loop(&mut self, chunk_loc: usize) {
-        let offset = self.chunk.code.len() - chunk_loc;
+        let offset = self.current_chunk().code.len() - chunk_loc;
         if offset > u16::MAX as usize {
             self.error("Loop body too large.");
         }
@@ -595,26 +2472,46 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
     }
 
     fn patch_jump(&mut self, opcode_loc: usize) {
-        let jump = self.chunk.code.len() - opcode_loc;
+        let jump = self.current_chunk().code.len() - opcode_loc;
 
         if jump > u16::MAX as usize {
             self.error("Too much code to jump over.");
         }
 
-        match self.chunk.code.get_mut(opcode_loc - 1).unwrap() {
+        match self.current_chunk().code.get_mut(opcode_loc - 1).unwrap() {
             (OpCode::JumpIfFalse { offset: target }, _) => *target = jump as u16,
+            (OpCode::JumpIfNotNil { offset: target }, _) => *target = jump as u16,
             (OpCode::Jump { offset: target }, _) => *target = jump as u16,
+            (OpCode::JumpIfArgPassed { offset: target, .. }, _) => *target = jump as u16,
             _ => unreachable!(),
         }
     }
 
     fn make_constant(&mut self, value: Value) -> u8 {
-        if self.chunk.constants.len() == u8::MAX as usize {
+        if self.current_chunk().constants.len() == u8::MAX as usize {
             self.error("Too many constants in one chunk.");
             // TOOD: rustic way
             return 0;
         }
-        self.chunk.add_constant(value)
+        self.current_chunk().add_constant(value)
+    }
+
+    /// Slot of `value` if the current chunk already has it, otherwise a new one
+    fn reuse_constant(&mut self, value: Value) -> u8 {
+        let existing = self
+            .current_chunk()
+            .constants
+            .iter()
+            .position(|x| match (x, &value) {
+                // -0 and 0 are equal but still different constants
+                (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+                (Value::Object(a), Value::Object(b)) => Rc::ptr_eq(a, b),
+                (a, b) => a == b,
+            });
+        match existing {
+            Some(location) => location as u8,
+            None => self.make_constant(value),
+        }
     }
 
     fn emit_constant(&mut self, value: Value) {
@@ -637,6 +2534,7 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
         if self.panic_mode {
             return;
         }
+        self.panic_mode = true;
 
         write!(self.stderr, "[line {}] Error", token.line).unwrap();
 
@@ -655,14 +2553,34 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
 
     fn get_rule(ty: TokenType) -> ParseRule<'a, 'b, WE> {
         match ty {
-            TokenType::LeftParen => {
-                ParseRule::new(Some(Compiler::grouping), None, Precedence::None)
-            }
+            TokenType::LeftParen => ParseRule::new(
+                Some(Compiler::grouping),
+                Some(Compiler::call),
+                Precedence::Call,
+            ),
             TokenType::RightParen => (None, None, Precedence::None).into(),
-            TokenType::LeftBrace => (None, None, Precedence::None).into(),
+            // Only reached in expression position, a statement starting with `{` is a block
+            TokenType::LeftBrace => ParseRule::new(Some(Compiler::map), None, Precedence::None),
             TokenType::RightBrace => (None, None, Precedence::None).into(),
+            TokenType::LeftBracket => ParseRule::new(
+                Some(Compiler::list),
+                Some(Compiler::subscript),
+                Precedence::Call,
+            ),
+            TokenType::RightBracket => (None, None, Precedence::None).into(),
+            TokenType::Colon => (None, None, Precedence::None).into(),
+            TokenType::Question => {
+                ParseRule::new(None, Some(Compiler::conditional), Precedence::Conditional)
+            }
+            TokenType::QuestionQuestion => {
+                ParseRule::new(None, Some(Compiler::coalesce), Precedence::Coalesce)
+            }
             TokenType::Comma => (None, None, Precedence::None).into(),
-            TokenType::Dot => (None, None, Precedence::None).into(),
+            TokenType::Dot => ParseRule::new(None, Some(Compiler::dot), Precedence::Call),
+            TokenType::DotDot | TokenType::DotDotEqual => {
+                ParseRule::new(None, Some(Compiler::binary), Precedence::Range)
+            }
+            TokenType::DotDotDot => (None, None, Precedence::None).into(),
             TokenType::Minus => ParseRule::new(
                 Some(Compiler::unary),
                 Some(Compiler::binary),
@@ -672,6 +2590,31 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
             TokenType::Semicolon => ParseRule::new(None, None, Precedence::None),
             TokenType::Slash => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
             TokenType::Star => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+            TokenType::Percent => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
+            TokenType::TildeSlash => {
+                ParseRule::new(None, Some(Compiler::binary), Precedence::Factor)
+            }
+            TokenType::StarStar => {
+                ParseRule::new(None, Some(Compiler::binary), Precedence::Exponent)
+            }
+            TokenType::Ampersand => {
+                ParseRule::new(None, Some(Compiler::binary), Precedence::BitAnd)
+            }
+            TokenType::Pipe => ParseRule::new(None, Some(Compiler::binary), Precedence::BitOr),
+            TokenType::Caret => ParseRule::new(None, Some(Compiler::binary), Precedence::BitXor),
+            TokenType::LessLess => ParseRule::new(None, Some(Compiler::binary), Precedence::Shift),
+            TokenType::GreaterGreater => {
+                ParseRule::new(None, Some(Compiler::binary), Precedence::Shift)
+            }
+            TokenType::Tilde => ParseRule::new(Some(Compiler::unary), None, Precedence::None),
+            TokenType::PlusPlus | TokenType::MinusMinus => {
+                ParseRule::new(Some(Compiler::prefix_increment), None, Precedence::None)
+            }
+            TokenType::Arrow => ParseRule::new(None, None, Precedence::None),
+            TokenType::PlusEqual
+            | TokenType::MinusEqual
+            | TokenType::StarEqual
+            | TokenType::SlashEqual => ParseRule::new(None, None, Precedence::None),
             TokenType::Bang => ParseRule::new(Some(Compiler::unary), None, Precedence::None),
             TokenType::BangEqual => {
                 ParseRule::new(None, Some(Compiler::binary), Precedence::Equality)
@@ -694,20 +2637,40 @@ impl<'a, 'b, WE: Write> Compiler<'a, 'b, WE> {
                 ParseRule::new(Some(Compiler::variable), None, Precedence::None)
             }
             TokenType::String => ParseRule::new(Some(Compiler::string), None, Precedence::None),
+            TokenType::Interpolation => {
+                ParseRule::new(Some(Compiler::interpolation), None, Precedence::None)
+            }
             TokenType::Number => ParseRule::new(Some(Compiler::number), None, Precedence::None),
             TokenType::And => ParseRule::new(None, Some(Compiler::and_), Precedence::And),
+            TokenType::Break => ParseRule::new(None, None, Precedence::None),
+            TokenType::Continue => ParseRule::new(None, None, Precedence::None),
             TokenType::Class => ParseRule::new(None, None, Precedence::None),
+            TokenType::Enum => ParseRule::new(None, None, Precedence::None),
+            TokenType::Is => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+            TokenType::Const => ParseRule::new(None, None, Precedence::None),
+            TokenType::Catch
+            | TokenType::Finally
+            | TokenType::Throw
+            | TokenType::Try
+            | TokenType::Yield => ParseRule::new(None, None, Precedence::None),
+            TokenType::As | TokenType::Import | TokenType::Export => {
+                ParseRule::new(None, None, Precedence::None)
+            }
             TokenType::Else => ParseRule::new(None, None, Precedence::None),
             TokenType::False => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
             TokenType::For => ParseRule::new(None, None, Precedence::None),
-            TokenType::Fun => ParseRule::new(None, None, Precedence::None),
+            TokenType::Fun => ParseRule::new(Some(Compiler::lambda), None, Precedence::None),
             TokenType::If => ParseRule::new(None, None, Precedence::None),
+            TokenType::Match | TokenType::Case | TokenType::Default => {
+                ParseRule::new(None, None, Precedence::None)
+            }
+            TokenType::In => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
             TokenType::Nil => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
             TokenType::Or => ParseRule::new(None, Some(Compiler::or_), Precedence::Or),
             TokenType::Print => ParseRule::new(None, None, Precedence::None),
             TokenType::Return => ParseRule::new(None, None, Precedence::None),
-            TokenType::Super => ParseRule::new(None, None, Precedence::None),
-            TokenType::This => ParseRule::new(None, None, Precedence::None),
+            TokenType::Super => ParseRule::new(Some(Compiler::super_), None, Precedence::None),
+            TokenType::This => ParseRule::new(Some(Compiler::this), None, Precedence::None),
             TokenType::True => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
             TokenType::Var => ParseRule::new(None, None, Precedence::None),
             TokenType::While => ParseRule::new(None, None, Precedence::None),
@@ -749,3 +2712,32 @@ impl<'a, 'b, WE: Write> ParseRule<'a, 'b, WE> {
         return (suffix, infix, precedence).into();
     }
 }
+
+/// Resolve escape sequences, the scanner already rejected invalid ones
+fn unescape(raw: &str) -> std::string::String {
+    let mut result = std::string::String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some('u') => {
+                // \u{XXXX}
+                chars.next();
+                let digits: std::string::String =
+                    chars.by_ref().take_while(|&c| c != '}').collect();
+                let code = u32::from_str_radix(&digits, 16).unwrap();
+                result.push(char::from_u32(code).unwrap());
+            }
+            // \" \\ and \$ stand for themselves
+            Some(c) => result.push(c),
+            None => (),
+        }
+    }
+    result
+}
@@ -34,16 +34,31 @@ impl OpCode {
             Greater => self.simple_instruction("OP_GREATER"),
 
             Add => self.simple_instruction("OP_ADD"),
+            Stringify => self.simple_instruction("OP_STRINGIFY"),
             Multiply => self.simple_instruction("OP_MULTIPLY"),
             Subtract => self.simple_instruction("OP_SUBTRACT"),
             Divide => self.simple_instruction("OP_DIVIDE"),
+            Modulo => self.simple_instruction("OP_MODULO"),
+            Power => self.simple_instruction("OP_POWER"),
+            IntegerDivide => self.simple_instruction("OP_INTEGER_DIVIDE"),
+            BitAnd => self.simple_instruction("OP_BIT_AND"),
+            BitOr => self.simple_instruction("OP_BIT_OR"),
+            BitXor => self.simple_instruction("OP_BIT_XOR"),
+            BitNot => self.simple_instruction("OP_BIT_NOT"),
+            ShiftLeft => self.simple_instruction("OP_SHIFT_LEFT"),
+            ShiftRight => self.simple_instruction("OP_SHIFT_RIGHT"),
 
             Print => self.simple_instruction("OP_PRINT"),
             Pop => self.simple_instruction("OP_POP"),
+            Dup { distance } => self.byte_instruction("OP_DUP", *distance),
+            Bury { depth } => self.byte_instruction("OP_BURY", *depth),
 
             DefineGlobal { location } => {
                 self.constant_instruction(chunk, "OP_DEFINE_GLOBAL", *location)
             }
+            DefineConstGlobal { location } => {
+                self.constant_instruction(chunk, "OP_DEFINE_CONST_GLOBAL", *location)
+            }
             GetGlobal { location } => self.constant_instruction(chunk, "OP_GET_GLOBAL", *location),
             SetGlobal { location } => self.constant_instruction(chunk, "OP_SET_GLOBAL", *location),
 
@@ -53,8 +68,70 @@ impl OpCode {
             JumpIfFalse { offset: target } => {
                 self.jump_instruction("OP_JUMP_IF_FALSE", *target, true)
             }
+            JumpIfNotNil { offset: target } => {
+                self.jump_instruction("OP_JUMP_IF_NOT_NIL", *target, true)
+            }
             Jump { offset: target } => self.jump_instruction("OP_JUMP", *target, true),
+            JumpIfArgPassed { offset, .. } => {
+                self.jump_instruction("OP_JUMP_IF_ARG_PASSED", *offset, true)
+            }
             Loop { offset: target } => self.jump_instruction("OP_LOOP", *target, false),
+            MatchJump { table } => self.byte_instruction("OP_MATCH_JUMP", *table),
+            Call { arg_count } => self.byte_instruction("OP_CALL", *arg_count),
+            Closure { location } => self.constant_instruction(chunk, "OP_CLOSURE", *location),
+            GetUpvalue { index } => self.byte_instruction("OP_GET_UPVALUE", *index),
+            SetUpvalue { index } => self.byte_instruction("OP_SET_UPVALUE", *index),
+            CloseUpvalue => self.simple_instruction("OP_CLOSE_UPVALUE"),
+            Class { location } => self.constant_instruction(chunk, "OP_CLASS", *location),
+            GetProperty { location } => {
+                self.constant_instruction(chunk, "OP_GET_PROPERTY", *location)
+            }
+            SetProperty { location } => {
+                self.constant_instruction(chunk, "OP_SET_PROPERTY", *location)
+            }
+            Method { location } => self.constant_instruction(chunk, "OP_METHOD", *location),
+            Getter { location } => self.constant_instruction(chunk, "OP_GETTER", *location),
+            Setter { location } => self.constant_instruction(chunk, "OP_SETTER", *location),
+            StaticMember { location } => {
+                self.constant_instruction(chunk, "OP_STATIC_MEMBER", *location)
+            }
+            Invoke {
+                location,
+                arg_count,
+            } => self.invoke_instruction(chunk, "OP_INVOKE", *location, *arg_count),
+            CallSpread { segments } => self.byte_instruction("OP_CALL_SPREAD", *segments),
+            InvokeSpread { location, segments } => {
+                self.invoke_instruction(chunk, "OP_INVOKE_SPREAD", *location, *segments)
+            }
+            SuperInvokeSpread { location, segments } => {
+                self.invoke_instruction(chunk, "OP_SUPER_INVOKE_SPREAD", *location, *segments)
+            }
+            Inherit => self.simple_instruction("OP_INHERIT"),
+            GetSuper { location } => self.constant_instruction(chunk, "OP_GET_SUPER", *location),
+            SuperInvoke {
+                location,
+                arg_count,
+            } => self.invoke_instruction(chunk, "OP_SUPER_INVOKE", *location, *arg_count),
+            Throw => self.simple_instruction("OP_THROW"),
+            Yield => self.simple_instruction("OP_YIELD"),
+            EndFinally => self.simple_instruction("OP_END_FINALLY"),
+            Import { location } => self.constant_instruction(chunk, "OP_IMPORT", *location),
+            Export { location } => self.constant_instruction(chunk, "OP_EXPORT", *location),
+            BuildList { count } => self.byte_instruction("OP_BUILD_LIST", *count),
+            BuildMap { count } => self.byte_instruction("OP_BUILD_MAP", *count),
+            BuildRange { inclusive: false } => self.simple_instruction("OP_BUILD_RANGE"),
+            BuildRange { inclusive: true } => self.simple_instruction("OP_BUILD_RANGE_INCLUSIVE"),
+            Contains => self.simple_instruction("OP_CONTAINS"),
+            IsVariant => self.simple_instruction("OP_IS_VARIANT"),
+            UnpackList { count } => self.byte_instruction("OP_UNPACK_LIST", *count),
+            UnpackField { location } => {
+                self.constant_instruction(chunk, "OP_UNPACK_FIELD", *location)
+            }
+            IndexGet => self.simple_instruction("OP_INDEX_GET"),
+            IndexSet => self.simple_instruction("OP_INDEX_SET"),
+            IterInit => self.simple_instruction("OP_ITER_INIT"),
+            IterHasNext { stack_idx } => self.byte_instruction("OP_ITER_HAS_NEXT", *stack_idx),
+            IterNext { stack_idx } => self.byte_instruction("OP_ITER_NEXT", *stack_idx),
         }
     }
 
@@ -72,6 +149,12 @@ impl OpCode {
         println!("{:<16} {:<4}", name, idx);
     }
 
+    fn invoke_instruction(&self, chunk: &Chunk, name: &str, location: u8, arg_count: u8) {
+        print!("{:<16} ({} args) {:<4} ", name, arg_count, location);
+        chunk.constants[location as usize].print(&mut std::io::stdout());
+        println!();
+    }
+
     fn constant_instruction(&self, chunk: &Chunk, name: &str, location: u8) {
         print!("{:<16} {:<4}", name, location);
         let value = chunk.constants[location as usize].clone();
@@ -4,9 +4,9 @@ use crate::{prelude::Value, value::LoxObject};
 
 const TABLE_MAX_LOAD: f32 = 0.75;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Entry {
-    key: Option<Rc<LoxObject>>,
+    key: Option<Value>,
     value: Value,
 }
 
@@ -18,7 +18,8 @@ impl Entry {
         }
     }
 }
-#[derive(Debug)]
+/// Keys have to be hashable, see [`Value::hash`]
+#[derive(Debug, Clone)]
 pub struct HashTable {
     /// Total filled entries in table
     count: usize,
@@ -34,7 +35,7 @@ impl HashTable {
     }
     /// Returns `true` if a new key is added
     /// `false` is existing key is updated
-    pub fn set(&mut self, key: Rc<LoxObject>, value: Value) -> bool {
+    pub fn set(&mut self, key: Value, value: Value) -> bool {
         // TODO(perf): Control the vector capacity grow instead of looking at len
         if self.count as f32 + 1.0 > self.entries.len() as f32 * TABLE_MAX_LOAD {
             let old_capacity = self.entries.len();
@@ -56,7 +57,7 @@ impl HashTable {
         return is_new_key;
     }
 
-    pub fn get(&mut self, key: &Rc<LoxObject>) -> Option<&mut Value> {
+    pub fn get(&mut self, key: &Value) -> Option<&mut Value> {
         if self.count == 0 {
             return None;
         }
@@ -67,7 +68,16 @@ impl HashTable {
         None
     }
 
-    pub fn delete(&mut self, key: &Rc<LoxObject>) -> bool {
+    /// Like `get`, without needing the table mutably
+    pub fn get_ref(&self, key: &Value) -> Option<&Value> {
+        if self.count == 0 {
+            return None;
+        }
+        let entry = &self.entries[Self::find_index(&self.entries, key)];
+        entry.key.as_ref().map(|_| &entry.value)
+    }
+
+    pub fn delete(&mut self, key: &Value) -> bool {
         if self.count == 0 {
             return false;
         }
@@ -84,51 +94,45 @@ impl HashTable {
 
     pub fn clear(&mut self) {
         self.entries.clear();
+        self.count = 0;
+    }
+
+    /// Live entries, in table order
+    pub fn iter(&self) -> impl Iterator<Item = (&Value, &Value)> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.key.as_ref().map(|key| (key, &entry.value)))
+    }
+
+    fn find_entry<'b>(entries: &'b mut [Entry], key: &Value) -> &'b mut Entry {
+        let index = Self::find_index(entries, key);
+        &mut entries[index]
     }
 
-    fn find_entry<'b>(entries: &'b mut [Entry], key: &Rc<LoxObject>) -> &'b mut Entry {
-        let mut index = key.as_string().unwrap().1 % entries.len() as u32;
+    fn find_index(entries: &[Entry], key: &Value) -> usize {
+        let hash = key.hash().expect("HashTable key must be hashable");
+        let mut index = hash % entries.len() as u32;
 
         let mut tombstone_index = None;
-        loop {
-            let entry = entries.get(index as usize).unwrap();
+        let found = loop {
+            let entry = &entries[index as usize];
             match &entry.key {
                 None => {
                     if entry.value.is_nil() {
-                        if let Some(i) = tombstone_index {
-                            unsafe {
-                                return entries.get_unchecked_mut(i as usize);
-                            };
-                        } else {
-                            unsafe {
-                                return entries.get_unchecked_mut(index as usize);
-                            };
-                        }
-                    } else {
-                        if tombstone_index.is_none() {
-                            tombstone_index = Some(index);
-                        }
+                        // Empty entry, reuse the first tombstone we passed if any
+                        break tombstone_index.unwrap_or(index);
+                    } else if tombstone_index.is_none() {
+                        tombstone_index = Some(index);
                     }
                 }
-                Some(x) => {
-                    unsafe {
-                        return entries.get_unchecked_mut(index as usize);
-                    };
-                }
-            }
-            match &entry.key {
-                Some(x) if Rc::ptr_eq(x, key) => unsafe {
-                    return entries.get_unchecked_mut(index as usize);
-                },
-                None => unsafe {
-                    return entries.get_unchecked_mut(index as usize);
-                },
-                _ => {}
+                Some(x) if x == key => break index,
+                Some(_) => {}
             }
 
             index += 1;
             index %= entries.len() as u32;
-        }
+        };
+        found as usize
     }
 
     fn adjust_capacity(&mut self, new_capacity: usize) {
@@ -164,19 +168,21 @@ impl HashTable {
                         return None;
                     }
                 }
-                Some(x) => {
-                    let v = x.as_string().unwrap();
-                    if v.1 == value.1 && v.0 == value.0 {
-                        return Some(x.clone());
+                Some(Value::Object(x)) => {
+                    if let Some(v) = x.as_string() {
+                        if v.1 == value.1 && v.0 == value.0 {
+                            return Some(x.clone());
+                        }
                     }
                 }
+                Some(_) => {}
             }
             index += 1;
             index %= self.entries.len() as u32;
         }
     }
 
-    fn table_add_all(from: &mut Self, to: &mut Self) {
+    pub fn table_add_all(from: &Self, to: &mut Self) {
         for i in 0..from.entries.len() {
             let entry = &from.entries[i];
             if let Some(x) = entry.key.as_ref() {
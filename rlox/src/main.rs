@@ -2,16 +2,8 @@
 use std::fs;
 use std::io;
 use std::io::Write;
-mod chunk;
-mod compiler;
-mod debug;
-mod hashtable;
-mod prelude;
-mod scanner;
-mod value;
-mod vm;
 
-use vm::VM;
+use rlox::vm::{self, VM};
 
 fn main() -> anyhow::Result<()> {
     let args: Vec<_> = std::env::args().collect();
@@ -60,7 +52,7 @@ fn run_file(path: &str) -> anyhow::Result<()> {
     let mut out = io::stdout();
     let mut err = io::stderr();
     let mut vm = VM::empty_new(&mut out, &mut err);
-    let result = vm.interpret(&source);
+    let result = vm.interpret_with_path(&source, std::path::Path::new(path));
 
     let error = match result {
         Err(x) => x,
@@ -1,4 +1,4 @@
-pub(crate) use crate::chunk::{Chunk, OpCode};
+pub(crate) use crate::chunk::{Chunk, Handler, JumpTable, OpCode};
 pub(crate) use crate::debug::disassemble_chunk;
 pub(crate) use crate::value::{Value, ValuePrinter};
 pub(crate) use std::rc::Rc;
@@ -9,6 +9,13 @@ pub enum TokenType {
     LeftBrace,
     /// }
     RightBrace,
+    /// [
+    LeftBracket,
+    /// ]
+    RightBracket,
+    Colon,
+    /// ?
+    Question,
     Comma,
     Dot,
     Minus,
@@ -16,6 +23,15 @@ pub enum TokenType {
     Semicolon,
     Slash,
     Star,
+    Percent,
+    /// &
+    Ampersand,
+    /// |
+    Pipe,
+    /// ^
+    Caret,
+    /// ~
+    Tilde,
 
     // one or two character
     Bang,
@@ -26,29 +42,74 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    /// **
+    StarStar,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    /// ??
+    QuestionQuestion,
+    /// =>
+    Arrow,
+    /// ++
+    PlusPlus,
+    /// --
+    MinusMinus,
+    /// ~/
+    TildeSlash,
+    /// <<
+    LessLess,
+    /// >>
+    GreaterGreater,
+    /// ..
+    DotDot,
+    /// ..=
+    DotDotEqual,
+    /// ...
+    DotDotDot,
 
     // Literals
     Identifier,
     String,
+    /// String segment ending right before an interpolated `${expr}`
+    Interpolation,
     Number,
 
     // Keywords
     And,
+    As,
+    Break,
+    Case,
+    Catch,
     Class,
+    Const,
+    Continue,
+    Default,
     Else,
+    Enum,
+    Export,
     False,
+    Finally,
     For,
     Fun,
     If,
+    Import,
+    In,
+    Is,
+    Match,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
+    Yield,
 
     // Semantic
     Error,
@@ -63,11 +124,15 @@ pub struct Token<'a> {
     pub line: usize,
 }
 
+/// Cloning gives a cheap way to look ahead without consuming tokens
+#[derive(Clone)]
 pub struct Scanner<'a> {
     src: &'a str,
     start: usize,
     current: usize,
     line: usize,
+    /// Unclosed `{` count for each `${` we are currently inside of
+    interpolations: Vec<usize>,
 }
 
 impl<'a> Scanner<'a> {
@@ -77,6 +142,7 @@ impl<'a> Scanner<'a> {
             start: 0,
             current: 0,
             line: 1,
+            interpolations: vec![],
         }
     }
 
@@ -94,19 +160,57 @@ impl<'a> Scanner<'a> {
         match c {
             '(' => return self.make_token(LeftParen),
             ')' => return self.make_token(RightParen),
-            '{' => return self.make_token(LeftBrace),
-            '}' => return self.make_token(RightBrace),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                return self.make_token(LeftBrace);
+            }
+            '}' => {
+                match self.interpolations.last_mut() {
+                    // Closes the `${`, rest of the string follows
+                    Some(0) => {
+                        self.interpolations.pop();
+                        return self.string();
+                    }
+                    Some(depth) => *depth -= 1,
+                    None => (),
+                }
+                return self.make_token(RightBrace);
+            }
+            '[' => return self.make_token(LeftBracket),
+            ']' => return self.make_token(RightBracket),
             ';' => return self.make_token(Semicolon),
+            ':' => return self.make_token(Colon),
+            '?' => self.conditional_token('?', QuestionQuestion, Question),
             ',' => return self.make_token(Comma),
+            '.' if self.match_char('.') => {
+                if self.match_char('.') {
+                    self.make_token(DotDotDot)
+                } else {
+                    self.conditional_token('=', DotDotEqual, DotDot)
+                }
+            }
             '.' => return self.make_token(Dot),
-            '-' => return self.make_token(Minus),
-            '+' => return self.make_token(Plus),
-            '/' => return self.make_token(Slash),
-            '*' => return self.make_token(Star),
+            '-' if self.match_char('-') => self.make_token(MinusMinus),
+            '-' => self.conditional_token('=', MinusEqual, Minus),
+            '+' if self.match_char('+') => self.make_token(PlusPlus),
+            '+' => self.conditional_token('=', PlusEqual, Plus),
+            '/' => self.conditional_token('=', SlashEqual, Slash),
+            '*' if self.match_char('*') => self.make_token(StarStar),
+            '*' => self.conditional_token('=', StarEqual, Star),
+            '%' => return self.make_token(Percent),
+            '&' => return self.make_token(Ampersand),
+            '|' => return self.make_token(Pipe),
+            '^' => return self.make_token(Caret),
+            '~' => self.conditional_token('/', TildeSlash, Tilde),
 
             '!' => self.conditional_token('=', BangEqual, Bang),
+            '=' if self.match_char('>') => self.make_token(Arrow),
             '=' => self.conditional_token('=', EqualEqual, Equal),
+            '<' if self.match_char('<') => self.make_token(LessLess),
             '<' => self.conditional_token('=', LessEqual, Less),
+            '>' if self.match_char('>') => self.make_token(GreaterGreater),
             '>' => self.conditional_token('=', GreaterEqual, Greater),
 
             '"' => self.string(),
@@ -195,6 +299,9 @@ impl<'a> Scanner<'a> {
                         while self.peek() != '\n' && !self.is_at_end() {
                             self.advance();
                         }
+                    } else {
+                        // A lone slash is division
+                        return;
                     }
                 }
                 _ => return,
@@ -202,10 +309,29 @@ impl<'a> Scanner<'a> {
         }
     }
 
+    /// Escapes are only validated here, the compiler does the unescaping
     fn string(&mut self) -> Token<'a> {
+        let mut error = None;
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            match self.peek() {
+                '\n' => self.line += 1,
+                '\\' => {
+                    self.advance();
+                    if let Err(msg) = self.escape() {
+                        error = error.or(Some(msg));
+                    }
+                    continue;
+                }
+                '$' if self.peek_next() == '{' => {
+                    self.advance();
+                    self.advance();
+                    self.interpolations.push(0);
+                    if let Some(msg) = error {
+                        return self.error_token(msg);
+                    }
+                    return self.make_token(TokenType::Interpolation);
+                }
+                _ => (),
             }
             self.advance();
         }
@@ -214,9 +340,47 @@ impl<'a> Scanner<'a> {
         }
         // Consume closing quote
         self.advance();
+        if let Some(msg) = error {
+            return self.error_token(msg);
+        }
         return self.make_token(TokenType::String);
     }
 
+    /// Validate the escape sequence following a `\`
+    fn escape(&mut self) -> Result<(), &'static str> {
+        if self.is_at_end() {
+            return Ok(());
+        }
+        match self.advance() {
+            'n' | 't' | 'r' | '"' | '\\' | '$' => Ok(()),
+            'u' => {
+                if !self.match_char('{') {
+                    return Err("Invalid unicode escape sequence.");
+                }
+                let start = self.current;
+                while self.peek().is_ascii_hexdigit() {
+                    self.advance();
+                }
+                let digits = &self.src[start..self.current];
+                if !self.match_char('}') || digits.is_empty() || digits.len() > 6 {
+                    return Err("Invalid unicode escape sequence.");
+                }
+                match u32::from_str_radix(digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                {
+                    Some(_) => Ok(()),
+                    None => Err("Invalid unicode escape sequence."),
+                }
+            }
+            '\n' => {
+                self.line += 1;
+                Err("Invalid escape sequence.")
+            }
+            _ => Err("Invalid escape sequence."),
+        }
+    }
+
     fn number(&mut self) -> Token<'a> {
         while self.peek().is_numeric() {
             self.advance();
@@ -253,21 +417,42 @@ impl<'a> Scanner<'a> {
     fn identifier_type(&self) -> TokenType {
         use TokenType::*;
         match self.src.chars().nth(self.start).unwrap() {
+            'a' if self.check_keyword("s", As) == As => return As,
             'a' => return self.check_keyword("nd", And),
-            'c' => return self.check_keyword("lass", Class),
+            'b' => return self.check_keyword("reak", Break),
+            'c' => {
+                if self.current - self.start > 1 {
+                    match self.src.chars().nth(self.start + 1).unwrap() {
+                        'a' if self.check_keyword("ase", Case) == Case => return Case,
+                        'a' => return self.check_keyword("atch", Catch),
+                        'l' => return self.check_keyword("lass", Class),
+                        'o' if self.check_keyword("onst", Const) == Const => return Const,
+                        'o' => return self.check_keyword("ontinue", Continue),
+                        _ => (),
+                    }
+                }
+            }
+            'd' => return self.check_keyword("efault", Default),
+            'e' if self.check_keyword("xport", Export) == Export => return Export,
+            'e' if self.check_keyword("num", Enum) == Enum => return Enum,
             'e' => return self.check_keyword("lse", Else),
             'f' => {
                 if self.current - self.start > 1 {
                     match self.src.chars().nth(self.start + 1).unwrap() {
                         // TODO(perf): unnecessary twice-check
                         'a' => return self.check_keyword("alse", False),
+                        'i' => return self.check_keyword("inally", Finally),
                         'o' => return self.check_keyword("or", For),
                         'u' => return self.check_keyword("un", Fun),
                         _ => (),
                     }
                 }
             }
+            'i' if self.check_keyword("mport", Import) == Import => return Import,
+            'i' if self.check_keyword("n", In) == In => return In,
+            'i' if self.check_keyword("s", Is) == Is => return Is,
             'i' => return self.check_keyword("f", If),
+            'm' => return self.check_keyword("atch", Match),
             'n' => return self.check_keyword("il", Nil),
             'o' => return self.check_keyword("r", Or),
             'p' => return self.check_keyword("rint", Print),
@@ -277,7 +462,9 @@ impl<'a> Scanner<'a> {
                 if self.current - self.start > 1 {
                     match self.src.chars().nth(self.start + 1).unwrap() {
                         // TODO(perf): unnecessary twice-check
+                        'h' if self.check_keyword("hrow", Throw) == Throw => return Throw,
                         'h' => return self.check_keyword("his", This),
+                        'r' if self.check_keyword("ry", Try) == Try => return Try,
                         'r' => return self.check_keyword("rue", True),
                         _ => (),
                     }
@@ -285,6 +472,7 @@ impl<'a> Scanner<'a> {
             }
             'v' => return self.check_keyword("ar", Var),
             'w' => return self.check_keyword("hile", While),
+            'y' => return self.check_keyword("ield", Yield),
             _ => (),
         };
         return TokenType::Identifier;
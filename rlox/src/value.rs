@@ -1,7 +1,12 @@
 use enum_kinds;
-use std::{io::Write, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    io::Write,
+    path::PathBuf,
+    rc::Rc,
+};
 
-use crate::prelude::Chunk;
+use crate::{hashtable::HashTable, prelude::Chunk};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ValueType {
@@ -21,15 +26,200 @@ pub enum LoxObject {
         hash: u32,
     },
     Function {
+        /// `None` for the top-level script
+        name: Option<String>,
+        arity: usize,
         chunk: Chunk,
+        /// What each upvalue of a closure over this function captures
+        upvalues: Vec<Capture>,
+        /// Parameters with a default, they follow the `arity` required ones
+        optional: usize,
+        /// A `...rest` parameter after all others collects extra arguments
+        variadic: bool,
+        /// Declared with `fun*`, calls return a `Generator` instead of running the body
+        generator: bool,
+    },
+    /// Functions only ever reach the user wrapped in a closure
+    Closure {
+        function: Rc<LoxObject>,
+        upvalues: Vec<Rc<RefCell<Upvalue>>>,
+        /// Module whose globals the function sees
+        module: Rc<LoxObject>,
+    },
+    Class {
+        name: String,
+        methods: RefCell<HashTable>,
+        /// Accessors run when the property of the same name is read or assigned,
+        /// they are looked up before the fields of an instance
+        getters: RefCell<HashTable>,
+        setters: RefCell<HashTable>,
+        /// Methods and fields declared `static`, properties of the class itself
+        statics: RefCell<HashTable>,
+        /// Members are copied down on inheritance, this only answers "is a" questions
+        superclass: RefCell<Option<Rc<LoxObject>>>,
+    },
+    Instance {
+        class: Rc<LoxObject>,
+        fields: RefCell<HashTable>,
+    },
+    /// A method closure along with the instance it was accessed from
+    BoundMethod {
+        receiver: Value,
+        method: Rc<LoxObject>,
+    },
+    List {
+        items: RefCell<Vec<Value>>,
+    },
+    /// Keys are restricted to values with a `Value::hash`
+    Map {
+        table: RefCell<HashTable>,
+    },
+    /// Declared with `enum`, variants are looked up as its properties
+    Enum {
+        name: String,
+        /// Constructors of variants with fields, values of the ones without
+        variants: HashTable,
+    },
+    /// One case of an enum, calling it builds an `EnumValue` if it has fields
+    Variant {
+        enum_name: String,
+        name: String,
+        fields: Vec<String>,
+    },
+    /// Tagged value, `values` line up with the fields of `variant`
+    EnumValue {
+        variant: Rc<LoxObject>,
+        values: Vec<Value>,
+    },
+    /// Numbers from `start` up to `end` in steps of one
+    Range {
+        start: f64,
+        end: f64,
+        /// `a..=b` includes `end`, `a..b` stops before it
+        inclusive: bool,
+    },
+    /// Progress of a `for` loop through a list, string or range.
+    /// Maps are iterated through a list of their keys.
+    Iterator {
+        source: Rc<LoxObject>,
+        /// Index for lists, byte offset for strings and steps taken for ranges
+        position: Cell<usize>,
+    },
+    /// Call of a `fun*` function, runs a step at a time through `hasNext()` and `next()`
+    Generator {
+        closure: Rc<LoxObject>,
+        state: RefCell<GeneratorState>,
+    },
+    /// Top-level scope of a source file, importing it yields this as a namespace
+    Module {
+        name: String,
+        /// Imports inside the module resolve relative to this file
+        path: Option<PathBuf>,
+        globals: RefCell<HashTable>,
+        /// Names of globals declared with `const`
+        consts: RefCell<HashTable>,
+        /// Names visible to importers, all globals are visible if nothing is exported
+        exports: RefCell<HashTable>,
+    },
+    /// Function implemented in Rust
+    Native {
+        name: String,
+        arity: usize,
+        function: NativeFunction,
     },
 }
 
+/// Receives the calling VM and the call arguments, an `Err` is reported as a runtime error
+pub type NativeFn = dyn Fn(&mut dyn NativeContext, &[Value]) -> Result<Value, String>;
+
+/// What a native can do with the VM that called it
+pub trait NativeContext {
+    /// Run `generator` up to its next `yield`, `None` once it's done.
+    /// Anything it throws is rethrown from the native's call once that returns.
+    fn resume(&mut self, generator: &Value) -> Result<Option<Value>, String>;
+}
+
+#[derive(Clone)]
+pub struct NativeFunction(pub Rc<NativeFn>);
+
+impl std::fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NativeFunction")
+    }
+}
+
+/// Where a closure finds a captured variable when it is created
+#[derive(Debug, Clone, Copy)]
+pub struct Capture {
+    /// Slot or upvalue index in the enclosing function
+    pub index: u8,
+    /// `true` if `index` is a local slot of the enclosing function,
+    /// `false` if it's one of the enclosing function's own upvalues
+    pub is_local: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum Upvalue {
+    /// Variable is still alive on the VM stack at this index
+    Open(usize),
+    /// Variable's scope ended, the upvalue owns it now
+    Closed(Value),
+    /// Variable belongs to a suspended generator, it's at this index of its stack
+    Suspended {
+        stack: Rc<RefCell<Vec<Value>>>,
+        index: usize,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum GeneratorState {
+    /// Waiting to run from `GeneratorFrame::ip`, either not started yet or paused at a `yield`
+    Suspended(GeneratorFrame),
+    /// `hasNext()` ran it up to a `yield` already, `next()` hands out the value
+    Yielded(GeneratorFrame, Value),
+    /// Its frame is on the VM, somewhere up the call stack
+    Running,
+    /// Returned or threw
+    Done,
+}
+
+/// Call frame of a generator while it's off the VM, with its own part of the stack
+#[derive(Debug, Clone)]
+pub struct GeneratorFrame {
+    pub ip: usize,
+    pub arg_count: usize,
+    /// Slots of the call, starting with the one of the callee or receiver
+    pub stack: Rc<RefCell<Vec<Value>>>,
+    /// Captured variables that live in `stack`, they are open again once resumed
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
 impl LoxObject {
     pub fn new_string(value: String) -> LoxObject {
         let hash = hash_string(&value);
         LoxObject::String { value, hash }
     }
+
+    pub fn new_class(name: String) -> LoxObject {
+        LoxObject::Class {
+            name,
+            methods: RefCell::new(HashTable::new()),
+            getters: RefCell::new(HashTable::new()),
+            setters: RefCell::new(HashTable::new()),
+            statics: RefCell::new(HashTable::new()),
+            superclass: RefCell::new(None),
+        }
+    }
+
+    pub fn new_module(name: String, path: Option<PathBuf>) -> LoxObject {
+        LoxObject::Module {
+            name,
+            path,
+            globals: RefCell::new(HashTable::new()),
+            consts: RefCell::new(HashTable::new()),
+            exports: RefCell::new(HashTable::new()),
+        }
+    }
 }
 
 impl LoxObject {
@@ -61,15 +251,43 @@ impl PartialEq<Value> for Value {
                     return false;
                 }
 
-                if x.is_string() && other.is_string() {
-                    return Rc::ptr_eq(x, other);
+                // Enum values compare by tag and fields
+                if let (
+                    LoxObject::EnumValue { variant, values },
+                    LoxObject::EnumValue {
+                        variant: other_variant,
+                        values: other_values,
+                    },
+                ) = (x.as_ref(), other.as_ref())
+                {
+                    return Rc::ptr_eq(variant, other_variant) && values == other_values;
                 }
-                return false;
+
+                // Strings are interned, so identity is equality for every other object kind
+                return Rc::ptr_eq(x, other);
             }
         }
     }
 }
 impl Value {
+    /// `None` for values that can't be used as a `HashTable` key
+    pub fn hash(&self) -> Option<u32> {
+        match self {
+            Self::Bool(true) => Some(3),
+            Self::Bool(false) => Some(5),
+            Self::Nil => Some(7),
+            Self::Number(x) => {
+                // 0.0 == -0.0, so they must hash the same
+                let bits = if *x == 0.0 { 0 } else { x.to_bits() };
+                Some((bits ^ (bits >> 32)) as u32)
+            }
+            Self::Object(x) => match x.as_ref() {
+                LoxObject::String { hash, .. } => Some(*hash),
+                _ => None,
+            },
+        }
+    }
+
     pub fn get_type(&self) -> ValueType {
         match self {
             Self::Bool(_) => ValueType::Bool,
@@ -105,13 +323,104 @@ impl ValuePrinter for Value {
             Object(x) => x,
         };
 
-        match obj.as_ref() {
-            LoxObject::String { value, hash: _ } => print!("{}", value),
-            _ => unreachable!(),
-        }
+        let _ = match obj.as_ref() {
+            LoxObject::String { value, hash: _ } => write!(writer, "{}", value),
+            LoxObject::Function { name: None, .. } => write!(writer, "<script>"),
+            LoxObject::Function {
+                name: Some(name), ..
+            } => write!(writer, "<fn {}>", name),
+            LoxObject::Closure { function, .. } => {
+                Value::Object(function.clone()).print(writer);
+                Ok(())
+            }
+            LoxObject::Class { name, .. } => write!(writer, "{}", name),
+            LoxObject::Instance { class, .. } => {
+                write!(writer, "{} instance", class.as_class().unwrap().0)
+            }
+            LoxObject::BoundMethod { method, .. } => {
+                Value::Object(method.clone()).print(writer);
+                Ok(())
+            }
+            LoxObject::Native { name, .. } => write!(writer, "<native fn {}>", name),
+            LoxObject::Generator { closure, .. } => {
+                match closure.as_closure().unwrap().0.as_function().unwrap().0 {
+                    Some(name) => write!(writer, "<generator {}>", name),
+                    None => write!(writer, "<generator>"),
+                }
+            }
+            LoxObject::Module { name, .. } => write!(writer, "<module {}>", name),
+            LoxObject::Enum { name, .. } => write!(writer, "<enum {}>", name),
+            LoxObject::Variant {
+                enum_name, name, ..
+            } => write!(writer, "<variant {}.{}>", enum_name, name),
+            LoxObject::EnumValue { variant, values } => {
+                let (enum_name, name, _) = variant.as_variant().unwrap();
+                let _ = write!(writer, "{}.{}", enum_name, name);
+                if !values.is_empty() {
+                    let _ = write!(writer, "(");
+                    for (i, value) in values.iter().enumerate() {
+                        if i != 0 {
+                            let _ = write!(writer, ", ");
+                        }
+                        value.print(writer);
+                    }
+                    let _ = write!(writer, ")");
+                }
+                Ok(())
+            }
+            LoxObject::Range {
+                start,
+                end,
+                inclusive,
+            } => {
+                let op = if *inclusive { "..=" } else { ".." };
+                write!(writer, "{}{}{}", start, op, end)
+            }
+            LoxObject::Iterator { .. } => write!(writer, "<iterator>"),
+            LoxObject::List { items } => {
+                let _ = write!(writer, "[");
+                for (i, item) in items.borrow().iter().enumerate() {
+                    if i != 0 {
+                        let _ = write!(writer, ", ");
+                    }
+                    item.print(writer);
+                }
+                write!(writer, "]")
+            }
+            LoxObject::Map { table } => {
+                let _ = write!(writer, "{{");
+                for (i, (key, value)) in table.borrow().iter().enumerate() {
+                    if i != 0 {
+                        let _ = write!(writer, ", ");
+                    }
+                    key.print(writer);
+                    let _ = write!(writer, ": ");
+                    value.print(writer);
+                }
+                write!(writer, "}}")
+            }
+        };
     }
 }
 
+/// Count of values a range steps through, saturating for huge or infinite ranges
+pub fn range_len(start: f64, end: f64, inclusive: bool) -> usize {
+    let span = end - start;
+    if inclusive && span >= 0.0 {
+        (span.floor() as usize).saturating_add(1)
+    } else if !inclusive && span > 0.0 {
+        span.ceil() as usize
+    } else {
+        0
+    }
+}
+
+/// Whether `x` is one of the values a range steps through
+pub fn range_contains(start: f64, end: f64, inclusive: bool, x: f64) -> bool {
+    let offset = x - start;
+    offset >= 0.0 && offset.fract() == 0.0 && offset < range_len(start, end, inclusive) as f64
+}
+
 pub fn hash_string(val: &str) -> u32 {
     let mut hash = 2166136261u32;
     for i in 0..val.len() {
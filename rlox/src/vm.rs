@@ -1,24 +1,119 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs;
 use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 
 // use crate::{compiler::compile, prelude::*};
-use crate::{compiler::Compiler, hashtable::HashTable, prelude::*, value::LoxObject};
+use crate::{
+    compiler::Compiler,
+    hashtable::HashTable,
+    prelude::*,
+    value::{
+        range_contains, range_len, GeneratorFrame, GeneratorState, LoxObject, NativeContext,
+        NativeFunction, Upvalue,
+    },
+};
 use smallvec;
 
+const FRAMES_MAX: usize = 64;
 const STACK_MAX: usize = 256;
 
-pub struct VM<'b, WS: Write, WE: Write> {
-    chunk: Chunk,
+struct CallFrame {
+    closure: Rc<LoxObject>,
 
     // Huh, the book says looking by index is slower them
     // looking by index. Why could that be? Due to additions?
     ip: usize,
 
+    /// Index in `VM::stack` of the first slot this function can use
+    slot_base: usize,
+
+    /// Arguments the caller passed, parameters past these use their default
+    arg_count: usize,
+
+    /// Set if this is a resumed generator, along with what its caller expects back
+    generator: Option<(Rc<LoxObject>, Resume)>,
+
+    /// Set for a getter run by `obj.name(args)`, what it returns is then called
+    /// with the `args` below its slots
+    call_result: Option<u8>,
+}
+
+/// Why a generator was resumed, decides what replaces it on the stack
+/// when it yields or finishes
+#[derive(Debug, Clone, Copy)]
+enum Resume {
+    /// `hasNext()`, whether it yielded, the value is kept for `next()`
+    HasNext,
+    /// `next()`, the yielded value or nil once it's done
+    Next,
+}
+
+impl Resume {
+    fn finished(self) -> Value {
+        match self {
+            Resume::HasNext => Value::Bool(false),
+            Resume::Next => Value::Nil,
+        }
+    }
+}
+
+impl CallFrame {
+    fn function(&self) -> &LoxObject {
+        self.closure.as_closure().unwrap().0
+    }
+
+    fn chunk(&self) -> &Chunk {
+        match self.function() {
+            LoxObject::Function { chunk, .. } => chunk,
+            _ => unreachable!(),
+        }
+    }
+
+    fn upvalue(&self, index: u8) -> &Rc<RefCell<Upvalue>> {
+        &self.closure.as_closure().unwrap().1[index as usize]
+    }
+
+    fn module(&self) -> &Rc<LoxObject> {
+        self.closure.as_closure().unwrap().2
+    }
+}
+
+pub struct VM<'b, WS: Write, WE: Write> {
+    frames: smallvec::SmallVec<[CallFrame; FRAMES_MAX]>,
+
     stack: smallvec::SmallVec<[Value; STACK_MAX]>,
 
     strings: HashTable,
 
+    /// Natives and anything else the host defines, visible from every module
     globals: HashTable,
 
+    /// Module of the script given to `interpret`
+    main_module: Rc<LoxObject>,
+    /// Every module loaded so far, keyed by canonical path
+    modules: HashMap<PathBuf, Rc<LoxObject>>,
+    /// Modules whose body is still running, outermost first
+    importing: Vec<PathBuf>,
+
+    /// Upvalues still pointing into `stack`
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+
+    /// Interned name of initializer methods
+    init_string: Rc<LoxObject>,
+    /// Interned names of the iteration protocol methods
+    iter_string: Rc<LoxObject>,
+    has_next_string: Rc<LoxObject>,
+    next_string: Rc<LoxObject>,
+
+    /// Class of the error objects runtime errors throw
+    error_class: Rc<LoxObject>,
+    /// Thrown value and the stack trace at the throw, until a handler catches it
+    exception: Option<(Value, String)>,
+    /// Frames below the generator being resumed by `resume`, which returns once they're on top
+    host_frames: usize,
+
     stdout: &'b mut WS,
     stderr: &'b mut WE,
 }
@@ -56,41 +151,319 @@ macro_rules! binary_op {
     }};
 }
 
+/// Like `binary_op!` but operands are converted to integers first
+macro_rules! bitwise_op {
+    ($vm:ident, $op:tt) => {{
+        let (a, b) = $vm.integer_operands()?;
+        $vm.stack.push(Value::Number((b $op a) as f64));
+    }};
+}
+
 impl<'a, 'b, WS: Write, WE: Write> VM<'b, WS, WE> {
-    pub fn new(chunk: Chunk, stdout: &'b mut WS, stderr: &'b mut WE) -> Self {
-        Self::new_with_strings(chunk, HashTable::new(), stdout, stderr)
+    pub fn new(stdout: &'b mut WS, stderr: &'b mut WE) -> Self {
+        Self::new_with_strings(HashTable::new(), stdout, stderr)
     }
 
     pub fn empty_new(stdout: &'b mut WS, stderr: &'b mut WE) -> Self {
-        Self::new(Chunk::new(), stdout, stderr)
+        Self::new(stdout, stderr)
     }
 
-    pub fn new_with_strings(
-        chunk: Chunk,
-        strings: HashTable,
-        stdout: &'b mut WS,
-        stderr: &'b mut WE,
-    ) -> Self {
-        Self {
-            chunk,
-            ip: 0,
+    pub fn new_with_strings(strings: HashTable, stdout: &'b mut WS, stderr: &'b mut WE) -> Self {
+        let mut vm = Self {
+            frames: smallvec::SmallVec::new(),
             stack: smallvec::SmallVec::new(),
             strings,
             globals: HashTable::new(),
+            main_module: Rc::new(LoxObject::new_module("main".to_string(), None)),
+            modules: HashMap::new(),
+            importing: vec![],
+            open_upvalues: vec![],
+            init_string: Rc::new(LoxObject::new_string(String::new())),
+            iter_string: Rc::new(LoxObject::new_string(String::new())),
+            has_next_string: Rc::new(LoxObject::new_string(String::new())),
+            next_string: Rc::new(LoxObject::new_string(String::new())),
+            error_class: Rc::new(LoxObject::new_string(String::new())),
+            exception: None,
+            host_frames: 0,
             stdout,
             stderr,
+        };
+        vm.init_string = vm.allocate_string("init".to_string());
+        vm.iter_string = vm.allocate_string("iter".to_string());
+        vm.has_next_string = vm.allocate_string("hasNext".to_string());
+        vm.next_string = vm.allocate_string("next".to_string());
+
+        let start = std::time::Instant::now();
+        vm.define_native("clock", 0, move |_| {
+            Ok(Value::Number(start.elapsed().as_secs_f64()))
+        });
+        vm.define_native("delete", 2, |args| {
+            let table = match args[0].as_object().map(|x| x.as_ref()) {
+                Some(LoxObject::Map { table }) => table,
+                _ => return Err("Can only delete from a map.".to_string()),
+            };
+            if args[1].hash().is_none() {
+                return Err("Map key must be a number, string, boolean or nil.".to_string());
+            }
+            Ok(Value::Bool(table.borrow_mut().delete(&args[1])))
+        });
+        vm.define_native("len", 1, |args| {
+            let len = match args[0].as_object().map(|x| x.as_ref()) {
+                Some(LoxObject::List { items }) => items.borrow().len(),
+                Some(LoxObject::Map { table }) => table.borrow().iter().count(),
+                Some(LoxObject::String { value, .. }) => value.chars().count(),
+                Some(LoxObject::Range {
+                    start,
+                    end,
+                    inclusive,
+                }) => range_len(*start, *end, *inclusive),
+                _ => {
+                    return Err(
+                        "Can only get the length of lists, maps, strings and ranges.".to_string(),
+                    )
+                }
+            };
+            Ok(Value::Number(len as f64))
+        });
+        vm.load_prelude();
+        vm
+    }
+
+    /// Run `PRELUDE` and make its globals visible to every module
+    fn load_prelude(&mut self) {
+        let module = Rc::new(LoxObject::new_module("prelude".to_string(), None));
+        let function = self.compile(PRELUDE).expect("prelude must compile");
+        self.start_module(function, module.clone())
+            .and_then(|_| self.run())
+            .expect("prelude must run");
+
+        let globals = module.as_module().unwrap().2;
+        HashTable::table_add_all(&globals.borrow(), &mut self.globals);
+        let name = Value::Object(self.allocate_string("Error".to_string()));
+        self.error_class = self
+            .globals
+            .get(&name)
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .clone();
+    }
+
+    /// Expose a Rust function to scripts as the global `name`
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&[Value]) -> Result<Value, String> + 'static,
+    {
+        self.define_native_with_context(name, arity, move |_, args| function(args));
+    }
+
+    /// Like `define_native`, for natives that need the VM, e.g. to resume generators
+    pub fn define_native_with_context<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&mut dyn NativeContext, &[Value]) -> Result<Value, String> + 'static,
+    {
+        let native = LoxObject::Native {
+            name: name.to_string(),
+            arity,
+            function: NativeFunction(Rc::new(function)),
+        };
+        let name = self.allocate_string(name.to_string());
+        self.globals
+            .set(Value::Object(name), Value::Object(Rc::new(native)));
+    }
+
+    /// Run `generator` up to its next `yield` from the host, `None` once it's done.
+    /// Between scripts, errors it doesn't catch are reported like `interpret` does.
+    /// Natives resume through [`NativeContext`] instead.
+    pub fn resume(&mut self, generator: &Value) -> Result<Option<Value>, InterpreterError> {
+        if !matches!(
+            generator.as_object().map(|x| x.as_ref()),
+            Some(LoxObject::Generator { .. })
+        ) {
+            writeln!(self.stderr, "Can only resume generators.").ok();
+            return Err(InterpreterError::RuntimeError);
+        }
+
+        let host_frames = std::mem::replace(&mut self.host_frames, self.frames.len());
+        let stack_len = self.stack.len();
+        let result = self.run_generator(generator);
+        self.host_frames = host_frames;
+        if result.is_err() {
+            self.stack.truncate(stack_len);
         }
+        result
     }
+
+    fn run_generator(&mut self, generator: &Value) -> Result<Option<Value>, InterpreterError> {
+        self.stack.push(generator.clone());
+        self.resume_generator(Resume::HasNext)?;
+        if self.frames.len() > self.host_frames {
+            self.run()?;
+        }
+        if is_falsey(self.stack.pop().unwrap()) {
+            return Ok(None);
+        }
+
+        // Already yielded, this just takes the value
+        self.stack.push(generator.clone());
+        self.resume_generator(Resume::Next)?;
+        Ok(self.stack.pop())
+    }
+
+    /// Globals persist across calls, imports resolve relative to the working directory
     pub fn interpret(&mut self, source: &'a str) -> InterpreterResult {
+        self.importing.clear();
+        let function = self.compile(source)?;
+        self.start_module(function, self.main_module.clone())?;
+        self.run()
+    }
+
+    /// Interpret `source` as the contents of the file at `path`
+    pub fn interpret_with_path(&mut self, source: &'a str, path: &Path) -> InterpreterResult {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let module = Rc::new(LoxObject::new_module(
+            module_name(&path),
+            Some(path.clone()),
+        ));
+        self.modules.insert(path.clone(), module.clone());
+        self.main_module = module.clone();
+        self.importing = vec![path];
+
+        let function = self.compile(source)?;
+        self.start_module(function, module)?;
+        self.run()
+    }
+
+    fn compile(&mut self, source: &str) -> Result<LoxObject, InterpreterError> {
         let old_strings = std::mem::replace(&mut self.strings, HashTable::new());
-        let (chunk, new_strings) = match Compiler::compile(source, old_strings, &mut self.stderr) {
-            Some(x) => x,
-            None => return Err(InterpreterError::CompileError),
-        };
-        self.ip = 0;
-        self.chunk = chunk;
+        let (function, new_strings) = Compiler::compile(source, old_strings, &mut self.stderr);
         self.strings = new_strings;
-        self.run()
+        function.ok_or(InterpreterError::CompileError)
+    }
+
+    /// Call the top-level `function` of `module`
+    fn start_module(&mut self, function: LoxObject, module: Rc<LoxObject>) -> InterpreterResult {
+        let closure = Rc::new(LoxObject::Closure {
+            function: Rc::new(function),
+            upvalues: vec![],
+            module,
+        });
+        self.stack.push(Value::Object(closure.clone()));
+        self.call(closure, 0)
+    }
+
+    /// Push the namespace of the module at `path`.
+    /// A module that isn't loaded yet gets its body called first, the namespace
+    /// is pushed once that returns.
+    fn import(&mut self, path: &str) -> InterpreterResult {
+        let base = match self.frame().module().as_module().unwrap().1 {
+            Some(file) => file.parent().map(Path::to_path_buf).unwrap_or_default(),
+            None => PathBuf::new(),
+        };
+        let resolved = match fs::canonicalize(base.join(path)) {
+            Ok(x) => x,
+            Err(_) => {
+                self.runtime_error(&format!("Could not find module '{}'.", path));
+                return Err(InterpreterError::RuntimeError);
+            }
+        };
+
+        if let Some(start) = self.importing.iter().position(|x| *x == resolved) {
+            let cycle: Vec<_> = self.importing[start..]
+                .iter()
+                .chain(std::iter::once(&resolved))
+                .map(|x| x.file_name().unwrap_or_default().to_string_lossy())
+                .collect();
+            self.runtime_error(&format!("Import cycle detected: {}.", cycle.join(" -> ")));
+            return Err(InterpreterError::RuntimeError);
+        }
+        if let Some(module) = self.modules.get(&resolved) {
+            self.stack.push(Value::Object(module.clone()));
+            return Ok(());
+        }
+
+        let source = match fs::read_to_string(&resolved) {
+            Ok(x) => x,
+            Err(_) => {
+                self.runtime_error(&format!("Could not read module '{}'.", path));
+                return Err(InterpreterError::RuntimeError);
+            }
+        };
+        let function = match self.compile(&source) {
+            Ok(x) => x,
+            Err(e) => {
+                self.stack.clear();
+                self.frames.clear();
+                self.open_upvalues.clear();
+                return Err(e);
+            }
+        };
+        let module = Rc::new(LoxObject::new_module(
+            module_name(&resolved),
+            Some(resolved.clone()),
+        ));
+        self.modules.insert(resolved.clone(), module.clone());
+        self.importing.push(resolved);
+        self.start_module(function, module)
+    }
+
+    /// Value of `name` as seen by importers of `module`
+    fn module_member(
+        &mut self,
+        module: &LoxObject,
+        name: &Value,
+    ) -> Result<Value, InterpreterError> {
+        let (module_name, _, globals, _, exports) = module.as_module().unwrap();
+        let exports = exports.borrow();
+        let visible = exports.iter().next().is_none() || exports.get_ref(name).is_some();
+        match globals.borrow_mut().get(name) {
+            Some(value) if visible => Ok(value.clone()),
+            _ => {
+                self.runtime_error(&format!(
+                    "Module '{}' has no member '{}'.",
+                    module_name,
+                    name.as_object().unwrap().as_string().unwrap().0
+                ));
+                Err(InterpreterError::RuntimeError)
+            }
+        }
+    }
+
+    /// Variant of an enum, or field of an enum value
+    fn enum_member(&mut self, object: &LoxObject, name: &Value) -> Result<Value, InterpreterError> {
+        let value = match object {
+            LoxObject::Enum { variants, .. } => variants.get_ref(name).cloned(),
+            value => enum_field(value, name),
+        };
+        if let Some(value) = value {
+            return Ok(value);
+        }
+
+        let name = &name.as_object().unwrap().as_string().unwrap().0;
+        match object {
+            LoxObject::Enum {
+                name: enum_name, ..
+            } => {
+                self.runtime_error(&format!("Enum '{}' has no variant '{}'.", enum_name, name));
+            }
+            LoxObject::EnumValue { variant, .. } => {
+                let (enum_name, variant_name, _) = variant.as_variant().unwrap();
+                self.runtime_error(&format!(
+                    "Variant '{}.{}' has no field '{}'.",
+                    enum_name, variant_name, name
+                ));
+            }
+            _ => unreachable!(),
+        }
+        Err(InterpreterError::RuntimeError)
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
     }
 
     fn peek(&self, distance: usize) -> Value {
@@ -103,7 +476,17 @@ impl<'a, 'b, WS: Write, WE: Write> VM<'b, WS, WE> {
 
     fn run(&mut self) -> InterpreterResult {
         loop {
-            let instruction = self.chunk.code[self.ip].0;
+            match self.execute() {
+                Err(InterpreterError::RuntimeError) if self.catch_exception() => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Run until the script finishes or something is thrown
+    fn execute(&mut self) -> InterpreterResult {
+        loop {
+            let instruction = self.frame().chunk().code[self.frame().ip].0;
 
             // Debug stuff
             #[cfg(feature = "trace_execution")]
@@ -115,20 +498,51 @@ impl<'a, 'b, WS: Write, WE: Write> VM<'b, WS, WE> {
                     print!(" ]");
                 }
                 println!();
-                instruction.show_disassemble(&self.chunk);
+                instruction.show_disassemble(self.frame().chunk());
             }
 
             use OpCode::*;
 
             // Execution
-            self.ip += 1;
+            self.frame_mut().ip += 1;
             match instruction {
                 Return => {
-                    // Exit interpreter;
-                    return Ok(());
+                    let result = self.stack.pop().unwrap();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slot_base);
+                    if self.frames.is_empty() && frame.generator.is_none() {
+                        // Pop the script function and exit interpreter
+                        self.stack.pop();
+                        return Ok(());
+                    }
+                    self.stack.truncate(frame.slot_base);
+                    if let Some((generator, resume)) = &frame.generator {
+                        // A finished generator has nothing more to hand out
+                        generator
+                            .as_generator()
+                            .unwrap()
+                            .1
+                            .replace(GeneratorState::Done);
+                        self.stack.push(resume.finished());
+                        if self.frames.len() == self.host_frames {
+                            // Resumed by the host or a native
+                            return Ok(());
+                        }
+                    } else if frame.function().as_function().unwrap().0.is_none() {
+                        // A module body finished, the import evaluates to its namespace
+                        self.importing.pop();
+                        self.stack.push(Value::Object(frame.module().clone()));
+                    } else if let Some(arg_count) = frame.call_result {
+                        // The getter's value takes the place of its receiver
+                        let slot = self.stack.len() - arg_count as usize - 1;
+                        self.stack[slot] = result.clone();
+                        self.call_value(result, arg_count)?;
+                    } else {
+                        self.stack.push(result);
+                    }
                 }
                 Constant { location } => {
-                    let constant = self.chunk.constants[location as usize].clone();
+                    let constant = self.read_constant(location);
                     self.stack.push(constant);
                 }
                 Negate => {
@@ -139,7 +553,8 @@ impl<'a, 'b, WS: Write, WE: Write> VM<'b, WS, WE> {
                             return Err(InterpreterError::RuntimeError);
                         }
                     };
-                    self.stack.push(Value::Number(val));
+                    self.stack.pop();
+                    self.stack.push(Value::Number(-val));
                 }
 
                 Nil => self.stack.push(Value::Nil),
@@ -182,9 +597,63 @@ impl<'a, 'b, WS: Write, WE: Write> VM<'b, WS, WE> {
                     }
                 },
                 // Add => binary_op!(self, Value::Number, +),
+                Stringify => {
+                    let value = self.stack.pop().unwrap();
+                    let is_string = matches!(
+                        value.as_object().map(|x| x.as_ref()),
+                        Some(LoxObject::String { .. })
+                    );
+                    if is_string {
+                        self.stack.push(value);
+                    } else {
+                        let mut buffer = vec![];
+                        value.print(&mut buffer);
+                        let str = self.allocate_string(String::from_utf8(buffer).unwrap());
+                        self.stack.push(Value::Object(str));
+                    }
+                }
                 Multiply => binary_op!(self, Value::Number, *),
                 Subtract => binary_op!(self, Value::Number, -),
                 Divide => binary_op!(self, Value::Number, /),
+                Modulo => binary_op!(self, Value::Number, %),
+                Power => {
+                    let (a, b) = self.number_operands()?;
+                    self.stack.push(Value::Number(b.powf(a)));
+                }
+                IntegerDivide => {
+                    let (a, b) = self.number_operands()?;
+                    if a == 0.0 {
+                        self.runtime_error("Integer division by zero.");
+                        return Err(InterpreterError::RuntimeError);
+                    }
+                    self.stack.push(Value::Number((b / a).floor()));
+                }
+                BitAnd => bitwise_op!(self, &),
+                BitOr => bitwise_op!(self, |),
+                BitXor => bitwise_op!(self, ^),
+                ShiftLeft | ShiftRight => {
+                    let (a, b) = self.integer_operands()?;
+                    if !(0..64).contains(&a) {
+                        self.runtime_error("Shift amount must be between 0 and 63.");
+                        return Err(InterpreterError::RuntimeError);
+                    }
+                    let result = match instruction {
+                        ShiftLeft => b << a,
+                        _ => b >> a,
+                    };
+                    self.stack.push(Value::Number(result as f64));
+                }
+                BitNot => {
+                    let val = match self.peek(0) {
+                        Value::Number(x) if x.fract() == 0.0 => x as i64,
+                        _ => {
+                            self.runtime_error("Operand must be an integer.");
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                    };
+                    self.stack.pop();
+                    self.stack.push(Value::Number(!val as f64));
+                }
 
                 Print => {
                     self.stack.pop().unwrap().print(self.stdout);
@@ -194,69 +663,1467 @@ impl<'a, 'b, WS: Write, WE: Write> VM<'b, WS, WE> {
                 Pop => {
                     self.stack.pop().unwrap();
                 }
+                Dup { distance } => {
+                    self.stack.push(self.peek(distance as usize));
+                }
+                Bury { depth } => {
+                    let value = self.stack.pop().unwrap();
+                    let index = self.stack.len() - depth as usize;
+                    self.stack.insert(index, value);
+                }
 
-                DefineGlobal { location } => {
-                    let name = self.read_constant(location).as_object().unwrap().clone();
+                DefineGlobal { location } | DefineConstGlobal { location } => {
+                    let name = self.read_constant(location);
+                    let module = self.frame().module().clone();
+                    let (_, _, globals, consts, _) = module.as_module().unwrap();
+                    if consts.borrow_mut().get(&name).is_some() {
+                        self.runtime_error(&format!(
+                            "Can't redefine constant '{}'.",
+                            name.as_object().unwrap().as_string().unwrap().0
+                        ));
+                        return Err(InterpreterError::RuntimeError);
+                    }
+                    if let DefineConstGlobal { .. } = instruction {
+                        consts.borrow_mut().set(name.clone(), Value::Nil);
+                    }
                     let val = self.peek(0);
-                    self.globals.set(name, val);
+                    globals.borrow_mut().set(name, val);
                     self.stack.pop().unwrap();
                 }
                 GetGlobal { location } => {
-                    let name = self.read_constant(location).as_object().unwrap().clone();
-                    match self.globals.get(&name) {
+                    let name = self.read_constant(location);
+                    let module = self.frame().module().clone();
+                    let globals = module.as_module().unwrap().2;
+                    // Module globals shadow the ones defined by the host
+                    let value = match globals.borrow_mut().get(&name) {
+                        Some(x) => Some(x.clone()),
+                        None => self.globals.get(&name).cloned(),
+                    };
+                    match value {
                         None => {
                             self.runtime_error(&format!(
                                 "Undefined variable '{}'",
-                                name.as_string().unwrap().0
+                                name.as_object().unwrap().as_string().unwrap().0
                             ));
                             return Err(InterpreterError::RuntimeError);
                         }
-                        Some(x) => self.stack.push(x.clone()),
+                        Some(x) => self.stack.push(x),
                     }
                 }
                 SetGlobal { location } => {
-                    let name = self.read_constant(location).as_object().unwrap().clone();
+                    let name = self.read_constant(location);
+                    let module = self.frame().module().clone();
+                    let (_, _, globals, consts, _) = module.as_module().unwrap();
+                    if consts.borrow_mut().get(&name).is_some() {
+                        self.runtime_error(&format!(
+                            "Can't assign to constant '{}'.",
+                            name.as_object().unwrap().as_string().unwrap().0
+                        ));
+                        return Err(InterpreterError::RuntimeError);
+                    }
                     let val = self.peek(0);
-                    if self.globals.set(name.clone(), val) {
-                        self.globals.delete(&name);
+                    if globals.borrow_mut().set(name.clone(), val) {
+                        globals.borrow_mut().delete(&name);
                         self.runtime_error(&format!(
                             "Undefined variable '{}'.",
-                            name.as_string().unwrap().0
+                            name.as_object().unwrap().as_string().unwrap().0
                         ));
                         return Err(InterpreterError::RuntimeError);
                     }
                 }
 
                 GetLocal { stack_idx } => {
-                    self.stack.push(self.stack[stack_idx as usize].clone());
+                    let slot = self.frame().slot_base + stack_idx as usize;
+                    self.stack.push(self.stack[slot].clone());
                 }
                 SetLocal { stack_idx } => {
-                    self.stack[stack_idx as usize] = self.peek(0);
+                    let slot = self.frame().slot_base + stack_idx as usize;
+                    self.stack[slot] = self.peek(0);
                 }
                 JumpIfFalse { offset } => {
                     if is_falsey(self.peek(0)) {
-                        self.ip += offset as usize;
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                JumpIfNotNil { offset } => {
+                    if !matches!(self.peek(0), Value::Nil) {
+                        self.frame_mut().ip += offset as usize;
                     }
                 }
                 Jump { offset } => {
-                    self.ip += offset as usize;
+                    self.frame_mut().ip += offset as usize;
+                }
+                Loop { offset } => self.frame_mut().ip -= offset as usize,
+                JumpIfArgPassed { index, offset } => {
+                    if self.frame().arg_count > index as usize {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                MatchJump { table } => {
+                    let table = &self.frame().chunk().jump_tables[table as usize];
+                    let target = match self.peek(0) {
+                        Value::Number(x)
+                            if x.fract() == 0.0
+                                && x >= table.min
+                                && x - table.min < table.dense.len() as f64 =>
+                        {
+                            table.dense[(x - table.min) as usize]
+                        }
+                        value if value.hash().is_some() => table
+                            .sparse
+                            .get_ref(&value)
+                            .map(|target| *target.as_number().unwrap() as usize),
+                        _ => None,
+                    };
+                    self.frame_mut().ip = target.unwrap_or(table.otherwise);
+                }
+                Call { arg_count } => {
+                    let callee = self.peek(arg_count as usize);
+                    self.call_value(callee, arg_count)?;
+                }
+                Closure { location } => {
+                    let function = self.read_constant(location).as_object().unwrap().clone();
+                    let captures = function.as_function().unwrap().3.clone();
+
+                    let slot_base = self.frame().slot_base;
+                    let upvalues = captures
+                        .iter()
+                        .map(|capture| {
+                            if capture.is_local {
+                                self.capture_upvalue(slot_base + capture.index as usize)
+                            } else {
+                                self.frame().upvalue(capture.index).clone()
+                            }
+                        })
+                        .collect();
+
+                    let closure = LoxObject::Closure {
+                        function,
+                        upvalues,
+                        module: self.frame().module().clone(),
+                    };
+                    self.stack.push(Value::Object(Rc::new(closure)));
+                }
+                GetUpvalue { index } => {
+                    let value = match &*self.frame().upvalue(index).borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                        Upvalue::Suspended { stack, index } => stack.borrow()[*index].clone(),
+                    };
+                    self.stack.push(value);
+                }
+                SetUpvalue { index } => {
+                    let value = self.peek(0);
+                    let upvalue = self.frame().upvalue(index).clone();
+                    let mut upvalue = upvalue.borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                        Upvalue::Suspended { stack, index } => stack.borrow_mut()[*index] = value,
+                    };
+                }
+                CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                }
+                Class { location } => {
+                    let name = self.read_constant(location);
+                    let class = LoxObject::new_class(
+                        name.as_object().unwrap().as_string().unwrap().0.clone(),
+                    );
+                    self.stack.push(Value::Object(Rc::new(class)));
+                }
+                GetProperty { location } => {
+                    let instance = self.peek(0);
+                    let (class, fields) = match instance.as_object().map(|x| x.as_ref()) {
+                        Some(LoxObject::Instance { class, fields }) => (class, fields),
+                        Some(module @ LoxObject::Module { .. }) => {
+                            let value =
+                                self.module_member(module, &self.read_constant(location))?;
+                            self.stack.pop();
+                            self.stack.push(value);
+                            continue;
+                        }
+                        Some(object @ (LoxObject::Enum { .. } | LoxObject::EnumValue { .. })) => {
+                            let value = self.enum_member(object, &self.read_constant(location))?;
+                            self.stack.pop();
+                            self.stack.push(value);
+                            continue;
+                        }
+                        Some(LoxObject::Class { statics, .. }) => {
+                            let name = self.read_constant(location);
+                            let value = statics.borrow_mut().get(&name).cloned();
+                            let value = match value {
+                                // Static methods stay bound to their class
+                                Some(Value::Object(method)) if method.as_closure().is_some() => {
+                                    Value::Object(Rc::new(LoxObject::BoundMethod {
+                                        receiver: instance.clone(),
+                                        method,
+                                    }))
+                                }
+                                Some(value) => value,
+                                None => {
+                                    self.runtime_error(&format!(
+                                        "Undefined property '{}'.",
+                                        name.as_object().unwrap().as_string().unwrap().0
+                                    ));
+                                    return Err(InterpreterError::RuntimeError);
+                                }
+                            };
+                            self.stack.pop();
+                            self.stack.push(value);
+                            continue;
+                        }
+                        _ => {
+                            self.runtime_error("Only instances have properties.");
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                    };
+                    let name = self.read_constant(location).as_object().unwrap().clone();
+
+                    // Getters come first, the instance on top of stack becomes their `this`
+                    let LoxObject::Class { getters, .. } = class.as_ref() else {
+                        unreachable!()
+                    };
+                    let getter = getters
+                        .borrow_mut()
+                        .get(&Value::Object(name.clone()))
+                        .cloned();
+                    if let Some(getter) = getter {
+                        self.call(getter.as_object().unwrap().clone(), 0)?;
+                        continue;
+                    }
+
+                    // Fields shadow methods
+                    let value = fields
+                        .borrow_mut()
+                        .get(&Value::Object(name.clone()))
+                        .cloned();
+                    match value {
+                        Some(value) => {
+                            self.stack.pop();
+                            self.stack.push(value);
+                        }
+                        None => self.bind_method(class.clone(), &name)?,
+                    }
+                }
+                SetProperty { location } => {
+                    let instance = self.peek(1);
+                    let (class, fields) = match instance.as_object().map(|x| x.as_ref()) {
+                        Some(LoxObject::Instance { class, fields }) => (class, fields),
+                        Some(LoxObject::Class { statics, .. }) => {
+                            let value = self.stack.pop().unwrap();
+                            statics
+                                .borrow_mut()
+                                .set(self.read_constant(location), value.clone());
+                            self.stack.pop();
+                            self.stack.push(value);
+                            continue;
+                        }
+                        _ => {
+                            self.runtime_error("Only instances have fields.");
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                    };
+                    let name = self.read_constant(location).as_object().unwrap().clone();
+
+                    // The setter gets the instance and value already on top of stack as its arguments
+                    let LoxObject::Class {
+                        getters, setters, ..
+                    } = class.as_ref()
+                    else {
+                        unreachable!()
+                    };
+                    let setter = setters
+                        .borrow_mut()
+                        .get(&Value::Object(name.clone()))
+                        .cloned();
+                    if let Some(setter) = setter {
+                        self.call(setter.as_object().unwrap().clone(), 1)?;
+                        continue;
+                    }
+                    if getters
+                        .borrow_mut()
+                        .get(&Value::Object(name.clone()))
+                        .is_some()
+                    {
+                        self.runtime_error(&format!(
+                            "Property '{}' has a getter but no setter.",
+                            name.as_string().unwrap().0
+                        ));
+                        return Err(InterpreterError::RuntimeError);
+                    }
+
+                    let value = self.stack.pop().unwrap();
+                    fields.borrow_mut().set(Value::Object(name), value.clone());
+                    self.stack.pop();
+                    self.stack.push(value);
+                }
+                Method { location } => {
+                    let name = self.read_constant(location).as_object().unwrap().clone();
+                    let method = self.stack.pop().unwrap();
+                    let class = self.peek(0);
+                    let LoxObject::Class { methods, .. } = class.as_object().unwrap().as_ref()
+                    else {
+                        unreachable!()
+                    };
+                    methods.borrow_mut().set(Value::Object(name), method);
+                }
+                Getter { location } => {
+                    let getter = self.stack.pop().unwrap();
+                    let class = self.peek(0);
+                    let LoxObject::Class { getters, .. } = class.as_object().unwrap().as_ref()
+                    else {
+                        unreachable!()
+                    };
+                    getters
+                        .borrow_mut()
+                        .set(self.read_constant(location), getter);
+                }
+                Setter { location } => {
+                    let setter = self.stack.pop().unwrap();
+                    let class = self.peek(0);
+                    let LoxObject::Class { setters, .. } = class.as_object().unwrap().as_ref()
+                    else {
+                        unreachable!()
+                    };
+                    setters
+                        .borrow_mut()
+                        .set(self.read_constant(location), setter);
+                }
+                StaticMember { location } => {
+                    let value = self.stack.pop().unwrap();
+                    let class = self.peek(0);
+                    let LoxObject::Class { statics, .. } = class.as_object().unwrap().as_ref()
+                    else {
+                        unreachable!()
+                    };
+                    statics
+                        .borrow_mut()
+                        .set(self.read_constant(location), value);
+                }
+                Invoke {
+                    location,
+                    arg_count,
+                } => {
+                    let name = self.read_constant(location).as_object().unwrap().clone();
+                    self.invoke(&name, arg_count)?;
+                }
+                CallSpread { segments } => {
+                    let arg_count = self.spread_arguments(segments)?;
+                    let callee = self.peek(arg_count as usize);
+                    self.call_value(callee, arg_count)?;
+                }
+                InvokeSpread { location, segments } => {
+                    let arg_count = self.spread_arguments(segments)?;
+                    let name = self.read_constant(location).as_object().unwrap().clone();
+                    self.invoke(&name, arg_count)?;
+                }
+                SuperInvokeSpread { location, segments } => {
+                    let name = self.read_constant(location).as_object().unwrap().clone();
+                    let superclass = self.stack.pop().unwrap().as_object().unwrap().clone();
+                    let arg_count = self.spread_arguments(segments)?;
+                    self.invoke_from_class(superclass, &name, arg_count)?;
+                }
+                Inherit => {
+                    let superclass = self.peek(1);
+                    if !matches!(
+                        superclass.as_object().map(|x| x.as_ref()),
+                        Some(LoxObject::Class { .. })
+                    ) {
+                        self.runtime_error("Superclass must be a class.");
+                        return Err(InterpreterError::RuntimeError);
+                    }
+                    let LoxObject::Class {
+                        methods,
+                        getters,
+                        setters,
+                        statics,
+                        ..
+                    } = superclass.as_object().unwrap().as_ref()
+                    else {
+                        unreachable!()
+                    };
+                    let subclass = self.peek(0);
+                    let LoxObject::Class {
+                        methods: sub_methods,
+                        getters: sub_getters,
+                        setters: sub_setters,
+                        statics: sub_statics,
+                        superclass: sub_superclass,
+                        ..
+                    } = subclass.as_object().unwrap().as_ref()
+                    else {
+                        unreachable!()
+                    };
+                    sub_superclass.replace(superclass.as_object().cloned());
+
+                    // Copy-down inheritance, members defined later in subclass override these
+                    HashTable::table_add_all(&methods.borrow(), &mut sub_methods.borrow_mut());
+                    HashTable::table_add_all(&getters.borrow(), &mut sub_getters.borrow_mut());
+                    HashTable::table_add_all(&setters.borrow(), &mut sub_setters.borrow_mut());
+                    HashTable::table_add_all(&statics.borrow(), &mut sub_statics.borrow_mut());
+                    self.stack.pop();
+                }
+                GetSuper { location } => {
+                    let name = self.read_constant(location).as_object().unwrap().clone();
+                    let superclass = self.stack.pop().unwrap().as_object().unwrap().clone();
+
+                    // Like a property read, the superclass getter runs with `this` on top of stack
+                    let LoxObject::Class { getters, .. } = superclass.as_ref() else {
+                        unreachable!()
+                    };
+                    let getter = getters
+                        .borrow_mut()
+                        .get(&Value::Object(name.clone()))
+                        .cloned();
+                    match getter {
+                        Some(getter) => self.call(getter.as_object().unwrap().clone(), 0)?,
+                        None => self.bind_method(superclass, &name)?,
+                    }
+                }
+                SuperInvoke {
+                    location,
+                    arg_count,
+                } => {
+                    let name = self.read_constant(location).as_object().unwrap().clone();
+                    let superclass = self.stack.pop().unwrap().as_object().unwrap().clone();
+                    self.invoke_from_class(superclass, &name, arg_count)?;
+                }
+                Yield => {
+                    let value = self.stack.pop().unwrap();
+                    let frame = self.frames.pop().unwrap();
+                    let (generator, resume) = frame.generator.clone().unwrap();
+                    let suspended = self.suspend_generator(frame);
+
+                    let state = generator.as_generator().unwrap().1;
+                    let result = match resume {
+                        Resume::HasNext => {
+                            state.replace(GeneratorState::Yielded(suspended, value));
+                            Value::Bool(true)
+                        }
+                        Resume::Next => {
+                            state.replace(GeneratorState::Suspended(suspended));
+                            value
+                        }
+                    };
+                    self.stack.push(result);
+                    if self.frames.len() == self.host_frames {
+                        // Resumed by the host or a native
+                        return Ok(());
+                    }
+                }
+                Throw => {
+                    let value = self.stack.pop().unwrap();
+                    self.throw(value);
+                    return Err(InterpreterError::RuntimeError);
+                }
+                EndFinally => {
+                    let is_throwing = !is_falsey(self.stack.pop().unwrap());
+                    let value = self.stack.pop().unwrap();
+                    if is_throwing {
+                        self.throw(value);
+                        return Err(InterpreterError::RuntimeError);
+                    }
+                }
+                Import { location } => {
+                    let path = self.read_constant(location);
+                    let path = path.as_object().unwrap().as_string().unwrap().0.clone();
+                    self.import(&path)?;
+                }
+                Export { location } => {
+                    let name = self.read_constant(location);
+                    let module = self.frame().module().clone();
+                    let exports = module.as_module().unwrap().4;
+                    exports.borrow_mut().set(name, Value::Nil);
+                }
+                BuildList { count } => {
+                    let start = self.stack.len() - count as usize;
+                    let items = self.stack.drain(start..).collect();
+                    let list = LoxObject::List {
+                        items: RefCell::new(items),
+                    };
+                    self.stack.push(Value::Object(Rc::new(list)));
+                }
+                BuildMap { count } => {
+                    let start = self.stack.len() - 2 * count as usize;
+                    let mut table = HashTable::new();
+                    for i in 0..count as usize {
+                        let key = self.stack[start + 2 * i].clone();
+                        self.map_key(&key)?;
+                        table.set(key, self.stack[start + 2 * i + 1].clone());
+                    }
+                    self.stack.truncate(start);
+                    let map = LoxObject::Map {
+                        table: RefCell::new(table),
+                    };
+                    self.stack.push(Value::Object(Rc::new(map)));
                 }
-                Loop { offset } => self.ip -= offset as usize,
+                BuildRange { inclusive } => {
+                    let (end, start) = match (self.peek(0), self.peek(1)) {
+                        (Value::Number(end), Value::Number(start)) => (end, start),
+                        _ => {
+                            self.runtime_error("Range bounds must be numbers.");
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                    };
+                    self.stack.pop();
+                    self.stack.pop();
+                    let range = LoxObject::Range {
+                        start,
+                        end,
+                        inclusive,
+                    };
+                    self.stack.push(Value::Object(Rc::new(range)));
+                }
+                Contains => {
+                    let container = self.peek(0);
+                    let value = self.peek(1);
+                    let found = match container.as_object().map(|x| x.as_ref()) {
+                        Some(LoxObject::Range {
+                            start,
+                            end,
+                            inclusive,
+                        }) => match value {
+                            Value::Number(x) => range_contains(*start, *end, *inclusive, x),
+                            _ => false,
+                        },
+                        Some(LoxObject::List { items }) => items.borrow().contains(&value),
+                        Some(LoxObject::Map { table }) => {
+                            value.hash().is_some() && table.borrow_mut().get(&value).is_some()
+                        }
+                        Some(LoxObject::String { value: string, .. }) => {
+                            match value.as_object().map(|x| x.as_ref()) {
+                                Some(LoxObject::String { value: part, .. }) => {
+                                    string.contains(part.as_str())
+                                }
+                                _ => {
+                                    self.runtime_error("Can only search a string for a string.");
+                                    return Err(InterpreterError::RuntimeError);
+                                }
+                            }
+                        }
+                        _ => {
+                            self.runtime_error(
+                                "Can only use 'in' with lists, maps, strings and ranges.",
+                            );
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                    };
+                    self.stack.pop();
+                    self.stack.pop();
+                    self.stack.push(Value::Bool(found));
+                }
+                IsVariant => {
+                    let variant = match self.peek(0).as_object().map(|x| x.as_ref()) {
+                        Some(LoxObject::Variant { .. }) => {
+                            self.peek(0).as_object().unwrap().clone()
+                        }
+                        Some(LoxObject::EnumValue { variant, values }) if values.is_empty() => {
+                            variant.clone()
+                        }
+                        _ => {
+                            self.runtime_error("Right operand of 'is' must be an enum variant.");
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                    };
+                    let is_variant = match self.peek(1).as_object().map(|x| x.as_ref()) {
+                        Some(LoxObject::EnumValue { variant: tag, .. }) => {
+                            Rc::ptr_eq(tag, &variant)
+                        }
+                        _ => false,
+                    };
+                    self.stack.pop();
+                    self.stack.pop();
+                    self.stack.push(Value::Bool(is_variant));
+                }
+                UnpackList { count } => {
+                    let list = self.peek(0);
+                    let items = match list.as_object().map(|x| x.as_ref()) {
+                        Some(LoxObject::List { items }) => items.borrow().clone(),
+                        _ => {
+                            self.runtime_error("Can only destructure a list with a list pattern.");
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                    };
+                    if items.len() != count as usize {
+                        self.runtime_error(&format!(
+                            "Expected {} elements to destructure but got {}.",
+                            count,
+                            items.len()
+                        ));
+                        return Err(InterpreterError::RuntimeError);
+                    }
+                    self.stack.pop();
+                    self.stack.extend(items);
+                }
+                UnpackField { location } => {
+                    let name = self.read_constant(location);
+                    let object = self.peek(0);
+                    let value = match object.as_object().map(|x| x.as_ref()) {
+                        Some(LoxObject::Instance { fields, .. }) => {
+                            fields.borrow_mut().get(&name).cloned()
+                        }
+                        Some(LoxObject::Map { table }) => table.borrow_mut().get(&name).cloned(),
+                        Some(value @ LoxObject::EnumValue { .. }) => enum_field(value, &name),
+                        _ => {
+                            self.runtime_error("Can only destructure instances, maps and enum values with an object pattern.");
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                    };
+                    let value = match value {
+                        Some(value) => value,
+                        None => {
+                            self.runtime_error(&format!(
+                                "Undefined property '{}'.",
+                                name.as_object().unwrap().as_string().unwrap().0
+                            ));
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                    };
+                    self.stack.pop();
+                    self.stack.push(value);
+                }
+                IndexGet => {
+                    let target = self.peek(1);
+                    let index = self.peek(0);
+                    let value = match (
+                        target.as_object().map(|x| x.as_ref()),
+                        index.as_object().map(|x| x.as_ref()),
+                    ) {
+                        (
+                            Some(LoxObject::List { items }),
+                            Some(LoxObject::Range {
+                                start,
+                                end,
+                                inclusive,
+                            }),
+                        ) => {
+                            let items = items.borrow();
+                            let len = range_len(*start, *end, *inclusive);
+                            let slice = if len == 0 {
+                                vec![]
+                            } else {
+                                // Both ends have to be valid indices
+                                let first = self.list_index(&Value::Number(*start), items.len())?;
+                                self.list_index(
+                                    &Value::Number(start + (len - 1) as f64),
+                                    items.len(),
+                                )?;
+                                items[first..first + len].to_vec()
+                            };
+                            Value::Object(Rc::new(LoxObject::List {
+                                items: RefCell::new(slice),
+                            }))
+                        }
+                        (Some(LoxObject::List { items }), _) => {
+                            let index = self.list_index(&self.peek(0), items.borrow().len())?;
+                            items.borrow()[index].clone()
+                        }
+                        (Some(LoxObject::Map { table }), _) => {
+                            let key = self.peek(0);
+                            self.map_key(&key)?;
+                            // Missing keys read as nil
+                            table.borrow_mut().get(&key).cloned().unwrap_or(Value::Nil)
+                        }
+                        _ => {
+                            self.runtime_error("Only lists and maps can be indexed.");
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                    };
+
+                    self.stack.pop();
+                    self.stack.pop();
+                    self.stack.push(value);
+                }
+                IndexSet => {
+                    let target = self.peek(2);
+                    let value = self.peek(0);
+                    match target.as_object().map(|x| x.as_ref()) {
+                        Some(LoxObject::List { items }) => {
+                            let index = self.list_index(&self.peek(1), items.borrow().len())?;
+                            items.borrow_mut()[index] = value.clone();
+                        }
+                        Some(LoxObject::Map { table }) => {
+                            let key = self.peek(1);
+                            self.map_key(&key)?;
+                            table.borrow_mut().set(key, value.clone());
+                        }
+                        _ => {
+                            self.runtime_error("Only lists and maps can be indexed.");
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                    };
+
+                    self.stack.pop();
+                    self.stack.pop();
+                    self.stack.pop();
+                    self.stack.push(value);
+                }
+                IterInit => {
+                    let iterable = self.peek(0);
+                    let source = match iterable.as_object().map(|x| x.as_ref()) {
+                        Some(
+                            LoxObject::List { .. }
+                            | LoxObject::String { .. }
+                            | LoxObject::Range { .. },
+                        ) => Some(iterable.as_object().unwrap().clone()),
+                        Some(LoxObject::Map { table }) => {
+                            // Iterate over a snapshot, the loop body may change the map
+                            let keys = table.borrow().iter().map(|(key, _)| key.clone()).collect();
+                            Some(Rc::new(LoxObject::List {
+                                items: RefCell::new(keys),
+                            }))
+                        }
+                        // Generators are iterators themselves
+                        Some(LoxObject::Generator { .. }) => None,
+                        Some(LoxObject::Instance { class, fields }) => {
+                            // Objects with an `iter()` method hand out their iterator,
+                            // any other instance has to be an iterator itself
+                            let name = Value::Object(self.iter_string.clone());
+                            let LoxObject::Class { methods, .. } = class.as_ref() else {
+                                unreachable!()
+                            };
+                            let has_iter = fields.borrow_mut().get(&name).is_some()
+                                || methods.borrow_mut().get(&name).is_some();
+                            if has_iter {
+                                self.invoke(&self.iter_string.clone(), 0)?;
+                            }
+                            None
+                        }
+                        _ => {
+                            self.runtime_error(
+                                "Can only iterate over lists, maps, strings, ranges, generators and instances.",
+                            );
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                    };
+
+                    if let Some(source) = source {
+                        let iterator = LoxObject::Iterator {
+                            source,
+                            position: Cell::new(0),
+                        };
+                        self.stack.pop();
+                        self.stack.push(Value::Object(Rc::new(iterator)));
+                    }
+                }
+                IterHasNext { stack_idx } => {
+                    let iterator = self.stack[self.frame().slot_base + stack_idx as usize].clone();
+                    match iterator.as_object().map(|x| x.as_ref()) {
+                        Some(LoxObject::Iterator { source, position }) => {
+                            let has_next = match source.as_ref() {
+                                LoxObject::List { items } => position.get() < items.borrow().len(),
+                                LoxObject::String { value, .. } => position.get() < value.len(),
+                                LoxObject::Range {
+                                    start,
+                                    end,
+                                    inclusive,
+                                } => position.get() < range_len(*start, *end, *inclusive),
+                                _ => unreachable!("iterator over {:?}", source.kind()),
+                            };
+                            self.stack.push(Value::Bool(has_next));
+                        }
+                        _ => {
+                            self.stack.push(iterator);
+                            self.invoke(&self.has_next_string.clone(), 0)?;
+                        }
+                    }
+                }
+                IterNext { stack_idx } => {
+                    let iterator = self.stack[self.frame().slot_base + stack_idx as usize].clone();
+                    match iterator.as_object().map(|x| x.as_ref()) {
+                        Some(LoxObject::Iterator { source, position }) => {
+                            let index = position.get();
+                            let value = match source.as_ref() {
+                                LoxObject::List { items } => {
+                                    position.set(index + 1);
+                                    items.borrow()[index].clone()
+                                }
+                                LoxObject::String { value, .. } => {
+                                    let ch = value[index..].chars().next().unwrap();
+                                    position.set(index + ch.len_utf8());
+                                    Value::Object(self.allocate_string(ch.to_string()))
+                                }
+                                LoxObject::Range { start, .. } => {
+                                    position.set(index + 1);
+                                    Value::Number(start + index as f64)
+                                }
+                                _ => unreachable!("iterator over {:?}", source.kind()),
+                            };
+                            self.stack.push(value);
+                        }
+                        _ => {
+                            self.stack.push(iterator);
+                            self.invoke(&self.next_string.clone(), 0)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reuse the open upvalue for `slot` if some closure already captured it,
+    /// so that all closures share the same variable.
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let existing = self
+            .open_upvalues
+            .iter()
+            .find(|x| matches!(&*x.borrow(), Upvalue::Open(s) if *s == slot));
+        if let Some(x) = existing {
+            return x.clone();
+        }
+
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue.clone());
+        upvalue
+    }
+
+    /// Move every variable at or above `last_slot` off the stack and into its upvalue
+    fn close_upvalues(&mut self, last_slot: usize) {
+        let stack = &self.stack;
+        self.open_upvalues.retain(|upvalue| {
+            let slot = match &*upvalue.borrow() {
+                Upvalue::Open(slot) => *slot,
+                Upvalue::Closed(_) | Upvalue::Suspended { .. } => unreachable!(),
+            };
+            if slot < last_slot {
+                return true;
+            }
+            *upvalue.borrow_mut() = Upvalue::Closed(stack[slot].clone());
+            false
+        });
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> InterpreterResult {
+        if let Value::Object(object) = callee {
+            match object.as_ref() {
+                LoxObject::Closure { .. } => return self.call(object, arg_count),
+                LoxObject::Class { methods, .. } => {
+                    let instance = LoxObject::Instance {
+                        class: object.clone(),
+                        fields: RefCell::new(HashTable::new()),
+                    };
+                    // Instance takes the place of the class on the stack, becoming `this` for init
+                    let slot = self.stack.len() - arg_count as usize - 1;
+                    self.stack[slot] = Value::Object(Rc::new(instance));
+
+                    let initializer = methods
+                        .borrow_mut()
+                        .get(&Value::Object(self.init_string.clone()))
+                        .cloned();
+                    match initializer {
+                        Some(initializer) => {
+                            let initializer = initializer.as_object().unwrap().clone();
+                            return self.call(initializer, arg_count);
+                        }
+                        None if arg_count != 0 => {
+                            self.runtime_error(&format!(
+                                "Expected 0 arguments but got {}.",
+                                arg_count
+                            ));
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                        None => return Ok(()),
+                    }
+                }
+                LoxObject::Variant { fields, .. } => {
+                    if arg_count as usize != fields.len() {
+                        self.runtime_error(&format!(
+                            "Expected {} arguments but got {}.",
+                            fields.len(),
+                            arg_count
+                        ));
+                        return Err(InterpreterError::RuntimeError);
+                    }
+                    let args_start = self.stack.len() - arg_count as usize;
+                    let value = LoxObject::EnumValue {
+                        variant: object.clone(),
+                        values: self.stack.drain(args_start..).collect(),
+                    };
+                    self.stack.pop();
+                    self.stack.push(Value::Object(Rc::new(value)));
+                    return Ok(());
+                }
+                LoxObject::BoundMethod { receiver, method } => {
+                    let slot = self.stack.len() - arg_count as usize - 1;
+                    self.stack[slot] = receiver.clone();
+                    return self.call(method.clone(), arg_count);
+                }
+                LoxObject::Native {
+                    arity, function, ..
+                } => {
+                    if arg_count as usize != *arity {
+                        self.runtime_error(&format!(
+                            "Expected {} arguments but got {}.",
+                            arity, arg_count
+                        ));
+                        return Err(InterpreterError::RuntimeError);
+                    }
+
+                    let args_start = self.stack.len() - arg_count as usize;
+                    let args = self.stack[args_start..].to_vec();
+                    let result = (function.0)(self, &args);
+                    match result {
+                        Ok(value) => {
+                            // Whatever a generator it resumed threw was handled by the native
+                            self.exception = None;
+                            // Discard the arguments and the native itself
+                            self.stack.truncate(args_start - 1);
+                            self.stack.push(value);
+                            return Ok(());
+                        }
+                        // A generator it resumed threw, that goes on from here
+                        Err(_) if self.exception.is_some() => {
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                        Err(msg) => {
+                            self.runtime_error(&msg);
+                            return Err(InterpreterError::RuntimeError);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        self.runtime_error("Can only call functions and classes.");
+        Err(InterpreterError::RuntimeError)
+    }
+
+    fn invoke(&mut self, name: &Rc<LoxObject>, arg_count: u8) -> InterpreterResult {
+        let receiver = self.peek(arg_count as usize);
+        let (class, fields) = match receiver.as_object().map(|x| x.as_ref()) {
+            Some(LoxObject::Instance { class, fields }) => (class, fields),
+            Some(module @ LoxObject::Module { .. }) => {
+                let value = self.module_member(module, &Value::Object(name.clone()))?;
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[slot] = value.clone();
+                return self.call_value(value, arg_count);
+            }
+            Some(object @ (LoxObject::Enum { .. } | LoxObject::EnumValue { .. })) => {
+                let value = self.enum_member(object, &Value::Object(name.clone()))?;
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[slot] = value.clone();
+                return self.call_value(value, arg_count);
+            }
+            Some(LoxObject::Class { statics, .. }) => {
+                let value = statics
+                    .borrow_mut()
+                    .get(&Value::Object(name.clone()))
+                    .cloned();
+                return match value {
+                    // The class stays in the receiver slot as `this` of a static method
+                    Some(Value::Object(method)) if method.as_closure().is_some() => {
+                        self.call(method, arg_count)
+                    }
+                    Some(value) => {
+                        let slot = self.stack.len() - arg_count as usize - 1;
+                        self.stack[slot] = value.clone();
+                        self.call_value(value, arg_count)
+                    }
+                    None => {
+                        self.runtime_error(&format!(
+                            "Undefined property '{}'.",
+                            name.as_string().unwrap().0
+                        ));
+                        Err(InterpreterError::RuntimeError)
+                    }
+                };
+            }
+            Some(LoxObject::Generator { .. }) => {
+                let resume = if Rc::ptr_eq(name, &self.has_next_string) {
+                    Resume::HasNext
+                } else if Rc::ptr_eq(name, &self.next_string) {
+                    Resume::Next
+                } else {
+                    self.runtime_error(&format!(
+                        "Undefined property '{}'.",
+                        name.as_string().unwrap().0
+                    ));
+                    return Err(InterpreterError::RuntimeError);
+                };
+                if arg_count != 0 {
+                    self.runtime_error(&format!("Expected 0 arguments but got {}.", arg_count));
+                    return Err(InterpreterError::RuntimeError);
+                }
+                return self.resume_generator(resume);
+            }
+            _ => {
+                self.runtime_error("Only instances have methods.");
+                return Err(InterpreterError::RuntimeError);
             }
+        };
+
+        // Getters come before fields, like for a property read
+        if let Some(getter) = self.find_getter(class, name) {
+            return self.invoke_getter(getter, arg_count);
+        }
+
+        // A field holding a function is called like any other value
+        let field = fields
+            .borrow_mut()
+            .get(&Value::Object(name.clone()))
+            .cloned();
+        if let Some(value) = field {
+            let slot = self.stack.len() - arg_count as usize - 1;
+            self.stack[slot] = value.clone();
+            return self.call_value(value, arg_count);
+        }
+
+        self.invoke_from_class(class.clone(), name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: Rc<LoxObject>,
+        name: &Rc<LoxObject>,
+        arg_count: u8,
+    ) -> InterpreterResult {
+        if let Some(getter) = self.find_getter(&class, name) {
+            return self.invoke_getter(getter, arg_count);
+        }
+
+        let LoxObject::Class { methods, .. } = class.as_ref() else {
+            unreachable!()
+        };
+        let method = methods
+            .borrow_mut()
+            .get(&Value::Object(name.clone()))
+            .cloned();
+        match method {
+            Some(method) => self.call(method.as_object().unwrap().clone(), arg_count),
+            None => {
+                self.runtime_error(&format!(
+                    "Undefined property '{}'.",
+                    name.as_string().unwrap().0
+                ));
+                Err(InterpreterError::RuntimeError)
+            }
+        }
+    }
+
+    fn find_getter(&self, class: &LoxObject, name: &Rc<LoxObject>) -> Option<Rc<LoxObject>> {
+        let LoxObject::Class { getters, .. } = class else {
+            unreachable!()
+        };
+        let getter = getters
+            .borrow_mut()
+            .get(&Value::Object(name.clone()))
+            .cloned();
+        getter.map(|x| x.as_object().unwrap().clone())
+    }
+
+    /// Run `getter` on the receiver below the `arg_count` arguments on top of stack,
+    /// what it returns is then called with those arguments
+    fn invoke_getter(&mut self, getter: Rc<LoxObject>, arg_count: u8) -> InterpreterResult {
+        let receiver = self.peek(arg_count as usize);
+        self.stack.push(receiver);
+        self.call(getter, 0)?;
+        self.frame_mut().call_result = Some(arg_count);
+        Ok(())
+    }
+
+    /// Replace the instance on top of stack with its method `name` bound to it
+    fn bind_method(&mut self, class: Rc<LoxObject>, name: &Rc<LoxObject>) -> InterpreterResult {
+        let LoxObject::Class { methods, .. } = class.as_ref() else {
+            unreachable!()
+        };
+        let method = methods
+            .borrow_mut()
+            .get(&Value::Object(name.clone()))
+            .cloned();
+        let method = match method {
+            Some(x) => x.as_object().unwrap().clone(),
+            None => {
+                self.runtime_error(&format!(
+                    "Undefined property '{}'.",
+                    name.as_string().unwrap().0
+                ));
+                return Err(InterpreterError::RuntimeError);
+            }
+        };
+
+        let bound = LoxObject::BoundMethod {
+            receiver: self.stack.pop().unwrap(),
+            method,
+        };
+        self.stack.push(Value::Object(Rc::new(bound)));
+        Ok(())
+    }
+
+    fn call(&mut self, closure: Rc<LoxObject>, arg_count: u8) -> InterpreterResult {
+        let function = closure.as_closure().unwrap().0.as_function().unwrap();
+        let (arity, optional, variadic, generator) =
+            (*function.1, *function.4, *function.5, *function.6);
+        let arg_count = arg_count as usize;
+        let max = arity + optional;
+        if arg_count < arity || (arg_count > max && !variadic) {
+            let expected = if variadic {
+                format!("at least {}", arity)
+            } else if optional > 0 {
+                format!("{} to {}", arity, max)
+            } else {
+                arity.to_string()
+            };
+            self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                expected, arg_count
+            ));
+            return Err(InterpreterError::RuntimeError);
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            self.runtime_error("Stack overflow.");
+            return Err(InterpreterError::RuntimeError);
+        }
+
+        // Parameters that weren't passed start as nil until their default is evaluated
+        for _ in arg_count..max {
+            self.stack.push(Value::Nil);
+        }
+        if variadic {
+            let extra = arg_count.saturating_sub(max);
+            let rest = self.stack.drain(self.stack.len() - extra..).collect();
+            self.stack.push(Value::Object(Rc::new(LoxObject::List {
+                items: RefCell::new(rest),
+            })));
+        }
+
+        let params = max + variadic as usize;
+        if generator {
+            // Nothing runs yet, the arguments wait on the generator's own stack
+            let slot_base = self.stack.len() - params - 1;
+            let frame = GeneratorFrame {
+                ip: 0,
+                arg_count,
+                stack: Rc::new(RefCell::new(self.stack.drain(slot_base..).collect())),
+                upvalues: vec![],
+            };
+            let generator = LoxObject::Generator {
+                closure,
+                state: RefCell::new(GeneratorState::Suspended(frame)),
+            };
+            self.stack.push(Value::Object(Rc::new(generator)));
+            return Ok(());
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            // Arguments along with the callee itself
+            slot_base: self.stack.len() - params - 1,
+            arg_count,
+            generator: None,
+            call_result: None,
+        });
+        Ok(())
+    }
+
+    /// Continue the generator on top of stack, it's replaced by what `resume` asks for
+    /// once the generator yields or returns.
+    fn resume_generator(&mut self, resume: Resume) -> InterpreterResult {
+        let generator = self.peek(0).as_object().unwrap().clone();
+        let (closure, state) = generator.as_generator().unwrap();
+        let frame = match state.replace(GeneratorState::Running) {
+            GeneratorState::Suspended(frame) => frame,
+            GeneratorState::Yielded(frame, value) => {
+                let result = match resume {
+                    Resume::HasNext => {
+                        state.replace(GeneratorState::Yielded(frame, value));
+                        Value::Bool(true)
+                    }
+                    Resume::Next => {
+                        state.replace(GeneratorState::Suspended(frame));
+                        value
+                    }
+                };
+                self.stack.pop();
+                self.stack.push(result);
+                return Ok(());
+            }
+            GeneratorState::Done => {
+                state.replace(GeneratorState::Done);
+                self.stack.pop();
+                self.stack.push(resume.finished());
+                return Ok(());
+            }
+            GeneratorState::Running => {
+                self.runtime_error("Generator is already running.");
+                return Err(InterpreterError::RuntimeError);
+            }
+        };
+
+        if self.frames.len() == FRAMES_MAX {
+            state.replace(GeneratorState::Suspended(frame));
+            self.runtime_error("Stack overflow.");
+            return Err(InterpreterError::RuntimeError);
+        }
+
+        // Its slots go where the generator was, captured variables point into them again
+        self.stack.pop();
+        let slot_base = self.stack.len();
+        self.stack.extend(frame.stack.take());
+        for upvalue in frame.upvalues {
+            let index = match &*upvalue.borrow() {
+                Upvalue::Suspended { index, .. } => *index,
+                _ => unreachable!(),
+            };
+            *upvalue.borrow_mut() = Upvalue::Open(slot_base + index);
+            self.open_upvalues.push(upvalue);
+        }
+
+        self.frames.push(CallFrame {
+            closure: closure.clone(),
+            ip: frame.ip,
+            slot_base,
+            arg_count: frame.arg_count,
+            generator: Some((generator.clone(), resume)),
+            call_result: None,
+        });
+        Ok(())
+    }
+
+    /// Move the slots of a generator's frame off the VM stack,
+    /// along with the open upvalues pointing into them
+    fn suspend_generator(&mut self, frame: CallFrame) -> GeneratorFrame {
+        let stack = Rc::new(RefCell::new(
+            self.stack.drain(frame.slot_base..).collect::<Vec<_>>(),
+        ));
+        let mut upvalues = vec![];
+        self.open_upvalues.retain(|upvalue| {
+            let slot = match &*upvalue.borrow() {
+                Upvalue::Open(slot) => *slot,
+                _ => unreachable!(),
+            };
+            if slot < frame.slot_base {
+                return true;
+            }
+            *upvalue.borrow_mut() = Upvalue::Suspended {
+                stack: stack.clone(),
+                index: slot - frame.slot_base,
+            };
+            upvalues.push(upvalue.clone());
+            false
+        });
+
+        GeneratorFrame {
+            ip: frame.ip,
+            arg_count: frame.arg_count,
+            stack,
+            upvalues,
+        }
+    }
+
+    /// Replace the `segments` argument lists on top of stack with their items
+    fn spread_arguments(&mut self, segments: u8) -> Result<u8, InterpreterError> {
+        let start = self.stack.len() - segments as usize;
+        let mut args = vec![];
+        for segment in self.stack.drain(start..).collect::<Vec<_>>() {
+            match segment.as_object().map(|x| x.as_ref()) {
+                Some(LoxObject::List { items }) => args.extend(items.borrow().iter().cloned()),
+                _ => {
+                    self.runtime_error("Can only spread a list.");
+                    return Err(InterpreterError::RuntimeError);
+                }
+            }
+        }
+        if args.len() > u8::MAX as usize {
+            self.runtime_error("Can't have more than 255 arguments.");
+            return Err(InterpreterError::RuntimeError);
         }
+        let arg_count = args.len() as u8;
+        self.stack.extend(args);
+        Ok(arg_count)
+    }
+
+    /// Validate `index` for a list of length `len`
+    fn list_index(&mut self, index: &Value, len: usize) -> Result<usize, InterpreterError> {
+        let index = match index {
+            Value::Number(x) if x.fract() == 0.0 => *x,
+            _ => {
+                self.runtime_error("List index must be an integer.");
+                return Err(InterpreterError::RuntimeError);
+            }
+        };
+        if index < 0.0 {
+            self.runtime_error("List index can't be negative.");
+            return Err(InterpreterError::RuntimeError);
+        }
+        if index >= len as f64 {
+            self.runtime_error(&format!(
+                "List index {} out of range for length {}.",
+                index, len
+            ));
+            return Err(InterpreterError::RuntimeError);
+        }
+        Ok(index as usize)
+    }
+
+    /// Pop two number operands, returned as (right, left)
+    fn number_operands(&mut self) -> Result<(f64, f64), InterpreterError> {
+        let (a, b) = match (self.peek(0), self.peek(1)) {
+            (Value::Number(a), Value::Number(b)) => (a, b),
+            _ => {
+                self.runtime_error("Operands must be numbers.");
+                return Err(InterpreterError::RuntimeError);
+            }
+        };
+        self.stack.pop();
+        self.stack.pop();
+        Ok((a, b))
+    }
+
+    /// Pop two integral number operands, returned as (right, left).
+    /// Values outside the i64 range saturate.
+    fn integer_operands(&mut self) -> Result<(i64, i64), InterpreterError> {
+        let (a, b) = match (self.peek(0), self.peek(1)) {
+            (Value::Number(a), Value::Number(b)) if a.fract() == 0.0 && b.fract() == 0.0 => {
+                (a as i64, b as i64)
+            }
+            _ => {
+                self.runtime_error("Operands must be integers.");
+                return Err(InterpreterError::RuntimeError);
+            }
+        };
+        self.stack.pop();
+        self.stack.pop();
+        Ok((a, b))
+    }
+
+    /// Only values with a `Value::hash` can be map keys
+    fn map_key(&mut self, key: &Value) -> InterpreterResult {
+        if key.hash().is_none() {
+            self.runtime_error("Map key must be a number, string, boolean or nil.");
+            return Err(InterpreterError::RuntimeError);
+        }
+        Ok(())
     }
 
     fn read_constant(&self, location: u8) -> Value {
-        return self.chunk.constants[location as usize].clone();
+        return self.frame().chunk().constants[location as usize].clone();
     }
 
+    /// Throw an `Error` with `msg` as its message
     fn runtime_error(&mut self, msg: &str) {
-        writeln!(self.stderr, "{}", msg);
+        let message = self.allocate_string(msg.to_string());
+        let mut fields = HashTable::new();
+        fields.set(self.message_key(), Value::Object(message));
+        let error = LoxObject::Instance {
+            class: self.error_class.clone(),
+            fields: RefCell::new(fields),
+        };
+        self.throw(Value::Object(Rc::new(error)));
+    }
+
+    /// Make `value` the pending exception, the caller has to return `RuntimeError`
+    fn throw(&mut self, value: Value) {
+        // Error objects remember where they were first thrown, other values are left alone
+        if let Some(LoxObject::Instance { class, fields }) = value.as_object().map(|x| x.as_ref()) {
+            let line_key = Value::Object(self.allocate_string("line".to_string()));
+            if self.is_error_class(class) && fields.borrow_mut().get(&line_key).is_none() {
+                let line = self.frame().chunk().code[self.frame().ip - 1].1;
+                fields
+                    .borrow_mut()
+                    .set(line_key, Value::Number(line as f64));
+            }
+        }
+
+        use std::fmt::Write as _;
+        let mut trace = String::new();
+        // Innermost frame first, like a stack trace
+        for frame in self.frames.iter().rev() {
+            let line = frame.chunk().code[frame.ip - 1].1;
+            let _ = write!(trace, "[line {}] in ", line);
+            let _ = match frame.function().as_function().unwrap().0 {
+                None if Rc::ptr_eq(frame.module(), &self.main_module) => writeln!(trace, "script"),
+                None => writeln!(trace, "module {}", frame.module().as_module().unwrap().0),
+                Some(name) => writeln!(trace, "{}()", name),
+            };
+        }
+        self.exception = Some((value, trace));
+    }
+
+    /// Unwind to the innermost handler covering the pending exception.
+    /// Returns `false` after reporting it if nothing catches it.
+    fn catch_exception(&mut self) -> bool {
+        let (exception, trace) = self.exception.take().unwrap();
+        while self.frames.len() > self.host_frames {
+            let frame = self.frames.last().unwrap();
+            let ip = frame.ip - 1;
+            let handler = frame
+                .chunk()
+                .handlers
+                .iter()
+                .find(|x| x.start <= ip && ip < x.end)
+                .copied();
+            if let Some(handler) = handler {
+                let depth = frame.slot_base + handler.stack_depth;
+                self.close_upvalues(depth);
+                self.stack.truncate(depth);
+                self.stack.push(exception);
+                self.frame_mut().ip = handler.target;
+                return true;
+            }
+
+            let frame = self.frames.pop().unwrap();
+            self.close_upvalues(frame.slot_base);
+            if let Some((generator, _)) = &frame.generator {
+                // A generator that threw is finished
+                generator
+                    .as_generator()
+                    .unwrap()
+                    .1
+                    .replace(GeneratorState::Done);
+            }
+            if frame.function().as_function().unwrap().0.is_none() && !self.frames.is_empty() {
+                // The import failed, a later import has to load the module again
+                if let Some(path) = self.importing.pop() {
+                    self.modules.remove(&path);
+                }
+            }
+        }
+
+        if self.host_frames > 0 {
+            // A native resumed the generator, its caller gets the chance to catch this
+            self.exception = Some((exception, trace));
+            return false;
+        }
+
+        // Uncaught, error objects report just their message
+        let message = match exception.as_object().map(|x| x.as_ref()) {
+            Some(LoxObject::Instance { fields, .. }) => {
+                fields.borrow_mut().get(&self.message_key()).cloned()
+            }
+            _ => None,
+        };
+        message.unwrap_or(exception).print(self.stderr);
+        writeln!(self.stderr).ok();
+        write!(self.stderr, "{}", trace).ok();
+        writeln!(self.stderr).ok();
 
-        let instruction = self.ip - 1;
-        let line = self.chunk.code[instruction].1;
-        writeln!(self.stderr, "[line {}] in script\n", line);
         self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        false
+    }
+
+    /// Whether `class` is the prelude `Error` or inherits from it
+    fn is_error_class(&self, class: &Rc<LoxObject>) -> bool {
+        let mut class = Some(class.clone());
+        while let Some(current) = class {
+            if Rc::ptr_eq(&current, &self.error_class) {
+                return true;
+            }
+            let LoxObject::Class { superclass, .. } = current.as_ref() else {
+                unreachable!()
+            };
+            class = superclass.borrow().clone();
+        }
+        false
+    }
+
+    fn message_key(&mut self) -> Value {
+        Value::Object(self.allocate_string("message".to_string()))
     }
 
     fn concatenate(&mut self) {
@@ -277,11 +2144,35 @@ impl<'a, 'b, WS: Write, WE: Write> VM<'b, WS, WE> {
             return x;
         }
         let str = Rc::new(lox_str);
-        self.strings.set(str.clone(), Value::Nil);
+        self.strings.set(Value::Object(str.clone()), Value::Nil);
         return str;
     }
 }
 
+impl<WS: Write, WE: Write> NativeContext for VM<'_, WS, WE> {
+    fn resume(&mut self, generator: &Value) -> Result<Option<Value>, String> {
+        if !matches!(
+            generator.as_object().map(|x| x.as_ref()),
+            Some(LoxObject::Generator { .. })
+        ) {
+            return Err("Can only resume generators.".to_string());
+        }
+        VM::resume(self, generator).map_err(|_| {
+            // The exception stays pending, the native only gets to see its message
+            let (exception, _) = self.exception.clone().unwrap();
+            let message = match exception.as_object().map(|x| x.as_ref()) {
+                Some(LoxObject::Instance { fields, .. }) => {
+                    fields.borrow_mut().get(&self.message_key()).cloned()
+                }
+                _ => None,
+            };
+            let mut text = vec![];
+            message.unwrap_or(exception).print(&mut text);
+            String::from_utf8_lossy(&text).into_owned()
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum InterpreterError {
     CompileError,
@@ -289,3 +2180,28 @@ pub enum InterpreterError {
 }
 
 pub type InterpreterResult = Result<(), InterpreterError>;
+
+/// Lox source run before any script, its globals are visible everywhere
+const PRELUDE: &str = r#"
+class Error {
+  init(message) {
+    this.message = message;
+  }
+}
+"#;
+
+/// File stem of a module path, what the module prints as
+fn module_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|x| x.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Field `name` of an enum value
+fn enum_field(value: &LoxObject, name: &Value) -> Option<Value> {
+    let (variant, values) = value.as_enum_value().unwrap();
+    let name = &name.as_object().unwrap().as_string().unwrap().0;
+    let fields = variant.as_variant().unwrap().2;
+    let index = fields.iter().position(|field| field == *name)?;
+    Some(values[index].clone())
+}
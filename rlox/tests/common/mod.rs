@@ -3,12 +3,22 @@
 
 macro_rules! test_execution {
     ($result:expr, $script_path:literal) => {{
+        test_execution!($result, $script_path, |_| {});
+    }};
+    // `$setup` receives the VM before the script runs, e.g. to define natives
+    ($result:expr, $script_path:literal, $setup:expr) => {{
+        test_execution!(@run $result, $script_path, $setup, |vm: &mut rlox::vm::VM<_, _>, value| {
+            vm.interpret(value)
+        });
+    }};
+    (@run $result:expr, $script_path:literal, $setup:expr, $interpret:expr) => {{
         let value = include_str!($script_path);
         let mut stdout = std::io::Cursor::new(Vec::new());
         let mut stderr = std::io::Cursor::new(Vec::new());
         let mut vm = rlox::vm::VM::empty_new(&mut stdout, &mut stderr);
-        // let res = vm.interpret($script);
-        let res = vm.interpret(value);
+        let setup: &dyn Fn(&mut rlox::vm::VM<_, _>) = &$setup;
+        setup(&mut vm);
+        let res = ($interpret)(&mut vm, value);
 
         drop(vm);
 
@@ -26,3 +36,12 @@ macro_rules! test_execution_success {
         test_execution!(Ok(()), $script_path);
     };
 }
+// Runs the fixture from its own path, so its imports resolve relative to it
+macro_rules! test_module_execution {
+    ($result:expr, $script_path:literal) => {{
+        test_execution!(@run $result, $script_path, |_| {}, |vm: &mut rlox::vm::VM<_, _>, value| {
+            let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/", $script_path);
+            vm.interpret_with_path(value, std::path::Path::new(path))
+        });
+    }};
+}
@@ -1,6 +1,7 @@
 #[macro_use]
 mod common;
 
+use rlox::value::Value;
 use rlox::vm::InterpreterError;
 
 #[test]
@@ -45,3 +46,517 @@ fn test_for_scope() {
         "fixtures/for-scope.lox"
     );
 }
+
+#[test]
+fn test_globals() {
+    test_execution_success!("fixtures/globals.lox");
+}
+
+#[test]
+fn test_string_output() {
+    test_execution_success!("fixtures/string_output.lox");
+}
+
+#[test]
+fn test_functions() {
+    test_execution_success!("fixtures/functions.lox");
+}
+
+#[test]
+fn test_functions_arity() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/functions_arity.lox"
+    );
+}
+
+#[test]
+fn test_if_condition() {
+    test_execution_success!("fixtures/if_condition.lox");
+}
+
+#[test]
+fn test_closures() {
+    test_execution_success!("fixtures/closures.lox");
+}
+
+#[test]
+fn test_classes() {
+    test_execution!(Err(InterpreterError::RuntimeError), "fixtures/classes.lox");
+}
+
+#[test]
+fn test_classes_field_error() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/classes_field_error.lox"
+    );
+}
+
+#[test]
+fn test_methods() {
+    test_execution!(Err(InterpreterError::RuntimeError), "fixtures/methods.lox");
+}
+
+#[test]
+fn test_methods_errors() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/methods_errors.lox"
+    );
+}
+
+#[test]
+fn test_inheritance() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/inheritance.lox"
+    );
+}
+
+#[test]
+fn test_inheritance_errors() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/inheritance_errors.lox"
+    );
+}
+
+#[test]
+fn test_natives() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/natives.lox",
+        |vm| vm.define_native("sum", 2, |args| match (&args[0], &args[1]) {
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+            _ => Err("sum() takes two numbers.".to_string()),
+        })
+    );
+}
+
+#[test]
+fn test_break_continue() {
+    test_execution_success!("fixtures/break_continue.lox");
+}
+
+#[test]
+fn test_break_errors() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/break_errors.lox"
+    );
+}
+
+#[test]
+fn test_negate() {
+    test_execution_success!("fixtures/negate.lox");
+}
+
+#[test]
+fn test_lists() {
+    test_execution!(Err(InterpreterError::RuntimeError), "fixtures/lists.lox");
+}
+
+#[test]
+fn test_lists_negative_index() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/lists_negative_index.lox"
+    );
+}
+
+#[test]
+fn test_maps() {
+    test_execution!(Ok(()), "fixtures/maps.lox");
+}
+
+#[test]
+fn test_maps_bad_key() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/maps_bad_key.lox"
+    );
+}
+
+#[test]
+fn test_error_cascade() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/error_cascade.lox"
+    );
+}
+
+#[test]
+fn test_strings() {
+    test_execution!(Ok(()), "fixtures/strings.lox");
+}
+
+#[test]
+fn test_strings_bad_escape() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/strings_bad_escape.lox"
+    );
+}
+
+#[test]
+fn test_operators() {
+    test_execution!(Ok(()), "fixtures/operators.lox");
+}
+
+#[test]
+fn test_operators_non_integer() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/operators_non_integer.lox"
+    );
+}
+
+#[test]
+fn test_compound_assignment() {
+    test_execution!(Ok(()), "fixtures/compound_assignment.lox");
+}
+
+#[test]
+fn test_compound_assignment_errors() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/compound_assignment_errors.lox"
+    );
+}
+
+#[test]
+fn test_increment_nested() {
+    test_execution_success!("fixtures/increment_nested.lox");
+}
+
+#[test]
+fn test_conditional() {
+    test_execution!(Ok(()), "fixtures/conditional.lox");
+}
+
+#[test]
+fn test_conditional_missing_colon() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/conditional_missing_colon.lox"
+    );
+}
+
+#[test]
+fn test_lambdas() {
+    test_execution!(Ok(()), "fixtures/lambdas.lox");
+}
+
+#[test]
+fn test_const() {
+    test_execution!(Ok(()), "fixtures/const.lox");
+}
+
+#[test]
+fn test_const_reads() {
+    test_execution_success!("fixtures/const_reads.lox");
+}
+
+#[test]
+fn test_const_local_assign() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/const_local_assign.lox"
+    );
+}
+
+#[test]
+fn test_const_global_assign() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/const_global_assign.lox"
+    );
+}
+
+#[test]
+fn test_const_global_redefine() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/const_global_redefine.lox"
+    );
+}
+
+#[test]
+fn test_modules() {
+    test_module_execution!(Err(InterpreterError::RuntimeError), "fixtures/modules.lox");
+}
+
+#[test]
+fn test_modules_destructuring() {
+    test_module_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/modules_destructuring.lox"
+    );
+}
+
+#[test]
+fn test_modules_generator() {
+    test_module_execution!(Ok(()), "fixtures/modules_generator.lox");
+}
+
+#[test]
+fn test_modules_cycle() {
+    test_module_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/modules_cycle.lox"
+    );
+}
+
+#[test]
+fn test_modules_missing() {
+    test_module_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/modules_missing.lox"
+    );
+}
+
+#[test]
+fn test_exceptions() {
+    test_execution!(Ok(()), "fixtures/exceptions.lox");
+}
+
+#[test]
+fn test_finally_return() {
+    test_execution!(Ok(()), "fixtures/finally_return.lox");
+}
+
+#[test]
+fn test_finally_loops() {
+    test_execution!(Ok(()), "fixtures/finally_loops.lox");
+}
+
+#[test]
+fn test_exceptions_uncaught() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/exceptions_uncaught.lox"
+    );
+}
+
+#[test]
+fn test_exceptions_errors() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/exceptions_errors.lox"
+    );
+}
+
+#[test]
+fn test_for_in() {
+    test_execution!(Ok(()), "fixtures/for_in.lox");
+}
+
+#[test]
+fn test_for_in_not_iterable() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/for_in_not_iterable.lox"
+    );
+}
+
+#[test]
+fn test_ranges() {
+    test_execution!(Ok(()), "fixtures/ranges.lox");
+}
+
+#[test]
+fn test_ranges_infinite() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/ranges_infinite.lox"
+    );
+}
+
+#[test]
+fn test_ranges_slice_out_of_range() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/ranges_slice_out_of_range.lox"
+    );
+}
+
+#[test]
+fn test_match() {
+    test_execution!(Ok(()), "fixtures/match.lox");
+}
+
+#[test]
+fn test_match_grouping() {
+    test_execution!(Ok(()), "fixtures/match_grouping.lox");
+}
+
+#[test]
+fn test_match_errors() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/match_errors.lox"
+    );
+}
+
+#[test]
+fn test_destructuring() {
+    test_execution!(Ok(()), "fixtures/destructuring.lox");
+}
+
+#[test]
+fn test_destructuring_shape() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/destructuring_shape.lox"
+    );
+}
+
+#[test]
+fn test_destructuring_missing_field() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/destructuring_missing_field.lox"
+    );
+}
+
+#[test]
+fn test_enums() {
+    test_execution!(Ok(()), "fixtures/enums.lox");
+}
+
+#[test]
+fn test_enums_unknown_variant() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/enums_unknown_variant.lox"
+    );
+}
+
+#[test]
+fn test_enums_arity() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/enums_arity.lox"
+    );
+}
+
+#[test]
+fn test_parameters() {
+    test_execution!(Ok(()), "fixtures/parameters.lox");
+}
+
+#[test]
+fn test_parameters_arity() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/parameters_arity.lox"
+    );
+}
+
+#[test]
+fn test_parameters_errors() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/parameters_errors.lox"
+    );
+}
+
+#[test]
+fn test_generators() {
+    test_execution!(Ok(()), "fixtures/generators.lox");
+}
+
+#[test]
+fn test_generators_running() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/generators_running.lox"
+    );
+}
+
+#[test]
+fn test_generators_errors() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/generators_errors.lox"
+    );
+}
+
+#[test]
+fn test_generators_native() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/generators_native.lox",
+        |vm| {
+            // Next value or nil once the generator is done
+            vm.define_native_with_context("step", 1, |vm, args| {
+                Ok(vm.resume(&args[0])?.unwrap_or(Value::Nil))
+            });
+            vm.define_native_with_context("sum", 1, |vm, args| {
+                let mut sum = 0.0;
+                while let Some(value) = vm.resume(&args[0])? {
+                    sum += value.as_number().unwrap();
+                }
+                Ok(Value::Number(sum))
+            });
+        }
+    );
+}
+
+#[test]
+fn test_generators_host() {
+    // The script hands generators over, the host resumes them in turns once it finished
+    let tasks = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+    let spawned = tasks.clone();
+    let mut stdout = std::io::Cursor::new(Vec::new());
+    let mut stderr = std::io::Cursor::new(Vec::new());
+    let mut vm = rlox::vm::VM::empty_new(&mut stdout, &mut stderr);
+    vm.define_native("spawn", 1, move |args| {
+        spawned.borrow_mut().push(args[0].clone());
+        Ok(Value::Nil)
+    });
+    let res = vm.interpret(include_str!("fixtures/generators_host.lox"));
+    pretty_assertions::assert_eq!(res, Ok(()));
+
+    let mut yielded = vec![];
+    while !tasks.borrow().is_empty() {
+        let task = tasks.borrow_mut().remove(0);
+        if let Some(value) = vm.resume(&task).unwrap() {
+            yielded.push(value);
+            tasks.borrow_mut().push(task);
+        }
+    }
+    pretty_assertions::assert_eq!(
+        yielded,
+        vec![Value::Number(1.0), Value::Number(1.0), Value::Number(2.0)]
+    );
+    pretty_assertions::assert_eq!(vm.resume(&Value::Nil), Err(InterpreterError::RuntimeError));
+
+    drop(vm);
+    let stdout = String::from_utf8(stdout.into_inner()).unwrap();
+    let stderr = String::from_utf8(stderr.into_inner()).unwrap();
+    insta::assert_snapshot!(stdout);
+    insta::assert_snapshot!(stderr);
+}
+
+#[test]
+fn test_accessors() {
+    test_execution!(Ok(()), "fixtures/accessors.lox");
+}
+
+#[test]
+fn test_accessors_no_setter() {
+    test_execution!(
+        Err(InterpreterError::RuntimeError),
+        "fixtures/accessors_no_setter.lox"
+    );
+}
+
+#[test]
+fn test_accessors_errors() {
+    test_execution!(
+        Err(InterpreterError::CompileError),
+        "fixtures/accessors_errors.lox"
+    );
+}